@@ -10,16 +10,17 @@ use anchor_lang::{
     Key, ToAccountInfo,
 };
 use solana_gateway::instruction::{
-    add_feature_to_network, add_gatekeeper, issue_vanilla, update_expiry, NetworkFeature,
+    add_feature_to_network, add_gatekeeper, issue_vanilla, set_state, update_expiry, NetworkFeature,
 };
-use solana_gateway::state::get_expire_address_with_seed;
+use solana_gateway::state::{get_expire_address_with_seed, GatewayTokenState};
 
 /// Parameters for a CPI operation on Gateway Tokens
 pub struct GatewayTokenParams<'a: 'b, 'b> {
     /// the rent payer
     pub payer: Signer<'a>,
-    /// the gatekeeper_network that the token is being issued for
-    pub gatekeeper_network: Account<'a, DerivedPass>,
+    /// the gatekeeper_network that the token is being issued for (the derived pass)
+    /// CHECK Already loaded as a derived pass by the program at this point
+    pub gatekeeper_network: AccountInfo<'a>,
     /// the recipient of the gateway token
     pub recipient: Signer<'a>,
     /// the recipient's gateway token account (to be initialised)
@@ -97,6 +98,61 @@ pub fn refresh_derived_pass(params: GatewayTokenParams<'_, '_>) -> Result<(), Er
     .map_err(|_| error!(ErrorCode::RefreshError))
 }
 
+/// Parameters for a CPI changing the state of an existing Gateway Token
+pub struct GatewayTokenStateParams<'a: 'b, 'b> {
+    /// the gateway token to change the state of
+    /// CHECK Verified by the Gateway program during the CPI call
+    pub gateway_token: AccountInfo<'a>,
+    /// the gatekeeper PDA
+    /// CHECK Verified by the Gateway program during the CPI call
+    pub gatekeeper: AccountInfo<'a>,
+    /// the gatekeeper account PDA (connecting the gatekeeper to the gk network)
+    /// CHECK Verified by the Gateway program during the CPI call
+    pub gatekeeper_account: AccountInfo<'a>,
+    /// the signer seeds for the gatekeeper PDA
+    pub authority_signer_seeds: &'b [&'b [u8]],
+    pub rent: Sysvar<'a, Rent>,
+    pub system_program: Program<'a, System>,
+}
+
+fn set_derived_pass_state(
+    params: GatewayTokenStateParams<'_, '_>,
+    state: GatewayTokenState,
+) -> Result<(), Error> {
+    invoke_signed(
+        &set_state(
+            params.gateway_token.key,
+            params.gatekeeper.key,
+            params.gatekeeper_account.key,
+            state,
+        ),
+        &[
+            params.gateway_token,
+            params.gatekeeper,
+            params.gatekeeper_account,
+            params.rent.to_account_info(),
+            params.system_program.to_account_info(),
+        ],
+        &[params.authority_signer_seeds],
+    )
+    .map_err(|_| error!(ErrorCode::SetStateError))
+}
+
+pub fn freeze_derived_pass(params: GatewayTokenStateParams<'_, '_>) -> Result<(), Error> {
+    msg!("Freezing gateway token {}", params.gateway_token.key);
+    set_derived_pass_state(params, GatewayTokenState::Frozen)
+}
+
+pub fn unfreeze_derived_pass(params: GatewayTokenStateParams<'_, '_>) -> Result<(), Error> {
+    msg!("Unfreezing gateway token {}", params.gateway_token.key);
+    set_derived_pass_state(params, GatewayTokenState::Active)
+}
+
+pub fn revoke_derived_pass(params: GatewayTokenStateParams<'_, '_>) -> Result<(), Error> {
+    msg!("Revoking gateway token {}", params.gateway_token.key);
+    set_derived_pass_state(params, GatewayTokenState::Revoked)
+}
+
 /// Parameters for a CPI Adding a Gatekeeper
 pub struct AddGatekeeperParams<'a> {
     /// the rent payer
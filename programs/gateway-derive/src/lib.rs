@@ -1,13 +1,28 @@
 mod gateway_client;
+mod migration;
+mod recipient_state;
+mod stats;
 mod util;
+mod voucher;
 
 use crate::{
     gateway_client::{
         add_derived_gatekeeper, issue_derived_pass, AddGatekeeperParams, GatewayTokenParams,
+        GatewayTokenStateParams,
     },
-    util::{DISCRIMINATOR_SIZE, FEE_SEED, GATEKEEPER_SEED, PUBKEY_SIZE, U64_SIZE, U8_SIZE},
+    migration::{load_derived_pass, FEE_VERSION, RESERVED_SPACE},
+    recipient_state::RECIPIENT_STATE_SEED,
+    stats::STATS_SEED,
+    util::{
+        DISCRIMINATOR_SIZE, FEE_OVERRIDE_SEED, FEE_SEED, FEE_VAULT_SEED, GATEKEEPER_SEED,
+        MAX_FEE_SPLITS, NETWORK_FEE_SEED, PUBKEY_SIZE, U16_SIZE, U32_SIZE, U64_SIZE, U8_SIZE,
+        WALLET_LINK_SEED,
+    },
+};
+use anchor_lang::{
+    prelude::*,
+    solana_program::{clock::UnixTimestamp, program::set_return_data},
 };
-use anchor_lang::{prelude::*, solana_program::clock::UnixTimestamp};
 use std::borrow::BorrowMut;
 use std::str::FromStr;
 
@@ -29,13 +44,26 @@ impl Id for Gateway {
 pub mod gateway_derive {
     use super::*;
     use crate::gateway_client::refresh_derived_pass;
-    use crate::util::{create_or_update_fee, validate_gateway_token, Action};
+    use crate::util::{
+        claim_from_fee_vault, create_or_update_fee, get_fee_destinations, validate_gateway_token,
+    };
     use crate::{
-        gateway_client::{add_expirable_on_use, AddExpirableOnUseParams},
+        gateway_client::{
+            add_expirable_on_use, freeze_derived_pass, revoke_derived_pass, unfreeze_derived_pass,
+            AddExpirableOnUseParams,
+        },
+        migration::{load_fee, save_derived_pass, write_migrated_account, DERIVED_PASS_VERSION},
+        recipient_state::{record_recipient_issue, record_recipient_refresh},
+        stats::{get_stats_address, record_activity, total_fees},
         util::{
-            get_expiry_time, get_validated_component_passes, pay_gatekeepers, validate_empty,
-            GATEKEEPER_SEED,
+            get_component_pass_owners, get_discount_networks, get_expiry_time,
+            get_validated_component_passes, parse_accounts, pay_gatekeepers,
+            split_component_accounts, validate_component_passes, validate_empty,
+            validate_fee_override_routing, validate_network_fee_routing,
+            validate_new_gateway_token, waive_batch_fees, FeeAdjustments, GATEKEEPER_SEED,
+            ISSUE_MANY_ACCOUNTS_PER_DERIVED_PASS,
         },
+        voucher::redeem_voucher,
     };
 
     pub fn initialize<'info>(
@@ -45,12 +73,26 @@ pub mod gateway_derive {
         gatekeeper_bump: u8,
         properties: DerivedPassProperties,
     ) -> Result<()> {
-        ctx.accounts.derived_pass.version = 0;
+        // a derived pass cannot be one of its own constituent passes
+        require!(
+            !source_gkns.contains(&ctx.accounts.derived_pass.key()),
+            ErrorCode::CircularDerivedPass
+        );
+        properties.validate()?;
+
+        ctx.accounts.derived_pass.version = DERIVED_PASS_VERSION;
         ctx.accounts.derived_pass.authority = *ctx.accounts.authority.key;
         ctx.accounts.derived_pass.gatekeeper_bump = gatekeeper_bump;
         ctx.accounts.derived_pass.source_gkns = source_gkns;
         ctx.accounts.derived_pass.properties = properties;
 
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.stats.version = 0;
+        ctx.accounts.stats.bump = *ctx.bumps.get("stats").unwrap();
+        ctx.accounts.stats.derived_pass = ctx.accounts.derived_pass.key();
+        ctx.accounts.stats.created_at = now;
+        ctx.accounts.stats.last_activity = now;
+
         let mut remaining_accounts = ctx.remaining_accounts.iter();
 
         // ensure the gatekeeper account is empty
@@ -84,97 +126,364 @@ pub mod gateway_derive {
     pub fn issue<'info>(
         ctx: Context<'_, '_, '_, 'info, Issue<'info>>,
         fee_bumps: Vec<u8>,
+        component_account_counts: Vec<u8>,
+        voucher: Option<FeeVoucher>,
+        _stats_bump: u8,
     ) -> Result<()> {
+        // derived passes of an older version are accepted until migrated (see migrate_derived_pass)
+        let mut derived_pass = load_derived_pass(&ctx.accounts.derived_pass)?;
         let system_program = &ctx.accounts.system_program;
         let gateway_token = ctx.accounts.gateway_token.to_account_info();
-        validate_empty(&gateway_token, system_program)?;
+        validate_new_gateway_token(&gateway_token, system_program)?;
 
-        if fee_bumps.len() != ctx.remaining_accounts.len() / 3 {
-            return Err(error!(ErrorCode::IncorrectFeeBumpCount));
-        }
+        let now = Clock::get()?.unix_timestamp;
+        derived_pass.record_issue(now)?;
+        save_derived_pass(&ctx.accounts.derived_pass, &derived_pass)?;
+        record_recipient_issue(
+            &ctx.accounts.derived_pass.key(),
+            &derived_pass,
+            &ctx.accounts.recipient.to_account_info(),
+            &ctx.accounts.recipient_state,
+            &system_program.to_account_info(),
+            now,
+        )?;
 
-        let parsed_component_passes = get_validated_component_passes(
+        // an optional wallet link may be passed in the shared accounts after the component pass accounts
+        let (component_accounts, shared_accounts) = split_component_accounts(
             ctx.remaining_accounts,
-            &ctx.accounts.derived_pass.source_gkns,
+            &component_account_counts,
+            fee_bumps.len(),
+        )?;
+        let component_pass_owners = get_component_pass_owners(
             ctx.accounts.recipient.key,
+            shared_accounts,
+            derived_pass.properties.allow_linked_wallets,
+        )?;
+
+        let parsed_component_passes = get_validated_component_passes(
+            &component_accounts,
+            shared_accounts,
+            &derived_pass.source_gkns,
+            &component_pass_owners,
             fee_bumps.as_slice(),
+            &ctx.accounts.derived_pass.key(),
+            &derived_pass.properties,
         )?;
 
-        pay_gatekeepers(
+        // a voucher, if passed, is verified and marked as used before any fees are paid
+        if let Some(voucher) = &voucher {
+            redeem_voucher(
+                voucher,
+                &ctx.accounts.derived_pass.key(),
+                &ctx.accounts.recipient,
+                Action::Issue,
+                shared_accounts,
+                &ctx.accounts.system_program,
+            )?;
+        }
+        let fee_adjustments = FeeAdjustments {
+            discount_networks: get_discount_networks(shared_accounts, ctx.accounts.recipient.key),
+            voucher: voucher.as_ref(),
+            caps: derived_pass.properties.fee_caps,
+        };
+        let fees = pay_gatekeepers(
             &mut ctx.accounts.recipient,
             parsed_component_passes,
+            &fee_adjustments,
             &ctx.accounts.system_program.to_account_info(),
             Action::Issue,
         )?;
+        record_activity(&ctx.accounts.stats, Action::Issue, total_fees(&fees)?, now)?;
+        emit!(FeesPaid {
+            recipient: ctx.accounts.recipient.key(),
+            derived_passes: vec![ctx.accounts.derived_pass.key()],
+            fees,
+        });
 
         issue_derived_pass(GatewayTokenParams {
             payer: ctx.accounts.recipient.clone(),
-            gatekeeper_network: ctx.accounts.derived_pass.clone(),
+            gatekeeper_network: ctx.accounts.derived_pass.to_account_info(),
             recipient: ctx.accounts.recipient.clone(),
             gateway_token,
             gatekeeper: ctx.accounts.derived_gatekeeper.to_account_info(),
             gatekeeper_account: ctx.accounts.derived_gatekeeper_account.to_account_info(),
             authority_signer_seeds: &[
                 GATEKEEPER_SEED,
-                &ctx.accounts.derived_pass.authority.to_bytes(),
-                &[ctx.accounts.derived_pass.gatekeeper_bump],
+                &derived_pass.authority.to_bytes(),
+                &[derived_pass.gatekeeper_bump],
             ],
-            expire_time: get_expiry_time(ctx.accounts.derived_pass.properties.expire_duration),
+            expire_time: get_expiry_time(&derived_pass.properties, None)?,
             rent: ctx.accounts.rent.clone(),
         })?;
 
         Ok(())
     }
 
+    /// Issue several derived passes to the recipient in one instruction.
+    /// The component passes are validated against each derived pass, and their fees are charged for each derived pass,
+    /// including any fee overrides for it (see create_fee_override), within the fee caps of that derived pass.
+    /// A fee with a batch discount (see Fee::batch_discount) is only charged once for all the derived passes.
+    /// The issuances and the fees charged for each derived pass are counted in its statistics.
+    /// Remaining accounts are of the form:
+    /// [derived pass accounts (see ISSUE_MANY_ACCOUNTS_PER_DERIVED_PASS) x derived_pass_count][component pass accounts (see split_component_accounts)][shared accounts]
+    pub fn issue_many<'info>(
+        ctx: Context<'_, '_, '_, 'info, IssueMany<'info>>,
+        derived_pass_count: u8,
+        fee_bumps: Vec<u8>,
+        component_account_counts: Vec<u8>,
+        stats_bumps: Vec<u8>,
+    ) -> Result<()> {
+        let derived_pass_account_count =
+            ISSUE_MANY_ACCOUNTS_PER_DERIVED_PASS * derived_pass_count as usize;
+        require!(
+            derived_pass_count > 0
+                && ctx.remaining_accounts.len() >= derived_pass_account_count
+                && stats_bumps.len() == derived_pass_count as usize,
+            ErrorCode::InvalidDerivedPassAccounts
+        );
+        let (derived_pass_accounts, component_accounts) =
+            ctx.remaining_accounts.split_at(derived_pass_account_count);
+
+        // an optional wallet link may be passed in the shared accounts after the component pass accounts
+        let (component_accounts, shared_accounts) = split_component_accounts(
+            component_accounts,
+            &component_account_counts,
+            fee_bumps.len(),
+        )?;
+
+        let system_program = &ctx.accounts.system_program;
+        let now = Clock::get()?.unix_timestamp;
+        let mut derived_passes = derived_pass_accounts
+            .chunks(ISSUE_MANY_ACCOUNTS_PER_DERIVED_PASS)
+            .zip(stats_bumps)
+            .map(|(accounts, stats_bump)| {
+                let mut derived_pass = load_derived_pass(&accounts[0])?;
+                let gateway_token = &accounts[1];
+                let derived_gatekeeper_account = &accounts[3];
+                let stats = &accounts[4];
+                let recipient_state = &accounts[5];
+
+                validate_new_gateway_token(gateway_token, system_program)?;
+                require!(
+                    *derived_gatekeeper_account.owner == Gateway::id()
+                        && *stats.key == get_stats_address(accounts[0].key, stats_bump)?,
+                    ErrorCode::InvalidDerivedPassAccounts
+                );
+
+                derived_pass.record_issue(now)?;
+                save_derived_pass(&accounts[0], &derived_pass)?;
+                record_recipient_issue(
+                    accounts[0].key,
+                    &derived_pass,
+                    &ctx.accounts.recipient.to_account_info(),
+                    recipient_state,
+                    &system_program.to_account_info(),
+                    now,
+                )?;
+
+                // the fees are parsed for each derived pass, so that its fee overrides apply
+                let parsed_component_passes = parse_accounts(
+                    &component_accounts,
+                    shared_accounts,
+                    fee_bumps.as_slice(),
+                    Some(accounts[0].key),
+                )?;
+                let component_pass_owners = get_component_pass_owners(
+                    ctx.accounts.recipient.key,
+                    shared_accounts,
+                    derived_pass.properties.allow_linked_wallets,
+                )?;
+                validate_component_passes(
+                    &parsed_component_passes,
+                    &derived_pass.source_gkns,
+                    &component_pass_owners,
+                    &derived_pass.properties,
+                )?;
+
+                Ok((derived_pass, accounts, parsed_component_passes))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let discount_networks = get_discount_networks(shared_accounts, ctx.accounts.recipient.key);
+        let mut batch_fees_charged = vec![false; component_accounts.len()];
+        let mut fees = vec![];
+        for (derived_pass, accounts, parsed_component_passes) in derived_passes.iter_mut() {
+            let fee_adjustments = FeeAdjustments {
+                discount_networks: discount_networks.clone(),
+                voucher: None,
+                caps: derived_pass.properties.fee_caps,
+            };
+            let derived_pass_fees = pay_gatekeepers(
+                &mut ctx.accounts.recipient,
+                waive_batch_fees(
+                    std::mem::take(parsed_component_passes),
+                    &mut batch_fees_charged,
+                ),
+                &fee_adjustments,
+                &ctx.accounts.system_program.to_account_info(),
+                Action::Issue,
+            )?;
+            record_activity(
+                &accounts[4],
+                Action::Issue,
+                total_fees(&derived_pass_fees)?,
+                now,
+            )?;
+            fees.extend(derived_pass_fees);
+        }
+        // the fee breakdowns of the derived passes, in order
+        emit!(FeesPaid {
+            recipient: ctx.accounts.recipient.key(),
+            derived_passes: derived_passes
+                .iter()
+                .map(|(_, accounts, _)| accounts[0].key())
+                .collect(),
+            fees,
+        });
+
+        derived_passes
+            .into_iter()
+            .try_for_each(|(derived_pass, accounts, _)| {
+                let authority = derived_pass.authority.to_bytes();
+                let gatekeeper_bump = [derived_pass.gatekeeper_bump];
+                let expire_time = get_expiry_time(&derived_pass.properties, None)?;
+
+                issue_derived_pass(GatewayTokenParams {
+                    payer: ctx.accounts.recipient.clone(),
+                    gatekeeper_network: accounts[0].clone(),
+                    recipient: ctx.accounts.recipient.clone(),
+                    gateway_token: accounts[1].clone(),
+                    gatekeeper: accounts[2].clone(),
+                    gatekeeper_account: accounts[3].clone(),
+                    authority_signer_seeds: &[GATEKEEPER_SEED, &authority, &gatekeeper_bump],
+                    expire_time,
+                    rent: ctx.accounts.rent.clone(),
+                })
+            })?;
+
+        Ok(())
+    }
+
     pub fn refresh<'info>(
         ctx: Context<'_, '_, '_, 'info, Refresh<'info>>,
         fee_bumps: Vec<u8>,
+        component_account_counts: Vec<u8>,
+        voucher: Option<FeeVoucher>,
+        _stats_bump: u8,
     ) -> Result<()> {
+        // derived passes of an older version are accepted until migrated (see migrate_derived_pass)
+        let derived_pass = load_derived_pass(&ctx.accounts.derived_pass)?;
         require!(
-            !ctx.accounts.derived_pass.properties.refresh_disabled,
+            !derived_pass.properties.refresh_disabled,
             ErrorCode::RefreshDisabled
         );
         let gateway_program = &ctx.accounts.gateway_program;
         let gateway_token = ctx.accounts.gateway_token.to_account_info();
-        validate_gateway_token(&gateway_token, gateway_program)?;
+        let current_expire_time = validate_gateway_token(
+            &gateway_token,
+            gateway_program,
+            &ctx.accounts.derived_pass.key(),
+            ctx.accounts.recipient.key,
+            derived_pass.properties.refresh_window,
+        )?
+        .expire_time;
 
-        if fee_bumps.len() != ctx.remaining_accounts.len() / 3 {
-            return Err(error!(ErrorCode::IncorrectFeeBumpCount));
+        let now = Clock::get()?.unix_timestamp;
+        if derived_pass.properties.issue_window_applies_to_refresh {
+            derived_pass.properties.check_issue_window(now)?;
         }
+        record_recipient_refresh(
+            &ctx.accounts.derived_pass.key(),
+            &derived_pass,
+            ctx.accounts.recipient.key,
+            &ctx.accounts.recipient_state,
+            now,
+        )?;
 
-        let parsed_component_passes = get_validated_component_passes(
+        // an optional wallet link may be passed in the shared accounts after the component pass accounts
+        let (component_accounts, shared_accounts) = split_component_accounts(
             ctx.remaining_accounts,
-            &ctx.accounts.derived_pass.source_gkns,
+            &component_account_counts,
+            fee_bumps.len(),
+        )?;
+        let component_pass_owners = get_component_pass_owners(
             ctx.accounts.recipient.key,
+            shared_accounts,
+            derived_pass.properties.allow_linked_wallets,
+        )?;
+
+        let parsed_component_passes = get_validated_component_passes(
+            &component_accounts,
+            shared_accounts,
+            &derived_pass.source_gkns,
+            &component_pass_owners,
             fee_bumps.as_slice(),
+            &ctx.accounts.derived_pass.key(),
+            &derived_pass.properties,
         )?;
 
-        pay_gatekeepers(
+        // a voucher, if passed, is verified and marked as used before any fees are paid
+        if let Some(voucher) = &voucher {
+            redeem_voucher(
+                voucher,
+                &ctx.accounts.derived_pass.key(),
+                &ctx.accounts.recipient,
+                Action::Refresh,
+                shared_accounts,
+                &ctx.accounts.system_program,
+            )?;
+        }
+        let fee_adjustments = FeeAdjustments {
+            discount_networks: get_discount_networks(shared_accounts, ctx.accounts.recipient.key),
+            voucher: voucher.as_ref(),
+            caps: derived_pass.properties.fee_caps,
+        };
+        let fees = pay_gatekeepers(
             &mut ctx.accounts.recipient,
             parsed_component_passes,
+            &fee_adjustments,
             &ctx.accounts.system_program.to_account_info(),
             Action::Refresh,
         )?;
+        record_activity(
+            &ctx.accounts.stats,
+            Action::Refresh,
+            total_fees(&fees)?,
+            now,
+        )?;
+        emit!(FeesPaid {
+            recipient: ctx.accounts.recipient.key(),
+            derived_passes: vec![ctx.accounts.derived_pass.key()],
+            fees,
+        });
 
+        let expire_time = get_expiry_time(&derived_pass.properties, current_expire_time)?;
         refresh_derived_pass(GatewayTokenParams {
             payer: ctx.accounts.recipient.clone(),
-            gatekeeper_network: ctx.accounts.derived_pass.clone(),
+            gatekeeper_network: ctx.accounts.derived_pass.to_account_info(),
             recipient: ctx.accounts.recipient.clone(),
             gateway_token,
             gatekeeper: ctx.accounts.derived_gatekeeper.to_account_info(),
             gatekeeper_account: ctx.accounts.derived_gatekeeper_account.to_account_info(),
             authority_signer_seeds: &[
                 GATEKEEPER_SEED,
-                &ctx.accounts.derived_pass.authority.to_bytes(),
-                &[ctx.accounts.derived_pass.gatekeeper_bump],
+                &derived_pass.authority.to_bytes(),
+                &[derived_pass.gatekeeper_bump],
             ],
-            expire_time: get_expiry_time(ctx.accounts.derived_pass.properties.expire_duration),
+            expire_time,
             rent: ctx.accounts.rent.clone(),
         })?;
 
+        // the new expiry time is returned to the caller (as a little-endian i64), if the derived pass expires
+        if let Some(expire_time) = expire_time {
+            msg!("Derived pass refreshed until {}", expire_time);
+            set_return_data(&expire_time.to_le_bytes());
+        }
+
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_fee(
         ctx: Context<CreateFee>,
         issue_amount: u64,
@@ -182,6 +491,9 @@ pub mod gateway_derive {
         percentage: u8,
         fee_type: u8, // Type: FeeType- Anchor does not yet provide mappings for enums
         mint: Option<Pubkey>,
+        discount: Option<FeeDiscount>,
+        routing: FeeRouting,
+        batch_discount: bool,
     ) -> Result<()> {
         create_or_update_fee(
             ctx.accounts.fee.borrow_mut(),
@@ -190,10 +502,13 @@ pub mod gateway_derive {
             percentage,
             fee_type,
             mint,
-        );
-        Ok(())
+            discount,
+            routing,
+            batch_discount,
+        )
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn update_fee(
         ctx: Context<UpdateFee>,
         issue_amount: u64,
@@ -201,6 +516,9 @@ pub mod gateway_derive {
         percentage: u8,
         fee_type: u8, // Type: FeeType- Anchor does not yet provide mappings for enums
         mint: Option<Pubkey>,
+        discount: Option<FeeDiscount>,
+        routing: FeeRouting,
+        batch_discount: bool,
     ) -> Result<()> {
         create_or_update_fee(
             ctx.accounts.fee.borrow_mut(),
@@ -209,13 +527,377 @@ pub mod gateway_derive {
             percentage,
             fee_type,
             mint,
+            discount,
+            routing,
+            batch_discount,
+        )
+    }
+
+    pub fn remove_fee(_ctx: Context<RemoveFee>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Schedule a change to the gatekeeper's fee amounts, taking effect at the effective time.
+    /// Replaces any change already pending.
+    pub fn schedule_fee_update(
+        ctx: Context<ScheduleFeeUpdate>,
+        issue_amount: u64,
+        refresh_amount: u64,
+        percentage: u8,
+        effective_time: UnixTimestamp,
+    ) -> Result<()> {
+        ctx.accounts.fee.schedule(
+            PendingFee {
+                issue_amount,
+                refresh_amount,
+                percentage,
+                effective_time,
+            },
+            Clock::get()?.unix_timestamp,
+        )
+    }
+
+    /// Apply a pending fee change that has taken effect. Can be called by anyone, for any kind of fee.
+    /// Until it is applied, a pending change that has taken effect is charged anyway,
+    /// so this only serves to keep the fee account up to date.
+    pub fn apply_pending_fee(ctx: Context<ApplyPendingFee>) -> Result<()> {
+        require!(ctx.accounts.fee.pending.is_some(), ErrorCode::NoPendingFee);
+        require!(
+            ctx.accounts.fee.apply_pending(Clock::get()?.unix_timestamp),
+            ErrorCode::PendingFeeNotEffective
         );
         Ok(())
     }
 
-    pub fn remove_fee(_ctx: Context<RemoveFee>) -> Result<()> {
+    /// Cancel a pending fee change that has not yet taken effect.
+    pub fn cancel_pending_fee(ctx: Context<CancelPendingFee>) -> Result<()> {
+        ctx.accounts
+            .fee
+            .cancel_pending(Clock::get()?.unix_timestamp)
+    }
+
+    /// Set a fee for the use of the gatekeeper's passes in a specific derived pass,
+    /// overriding the gatekeeper's fee on the gatekeeper network, e.g. to offer a lower rate to a partner.
+    /// The override is paid directly, as it cannot escrow fees (see validate_fee_override_routing).
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_fee_override(
+        ctx: Context<CreateFeeOverride>,
+        issue_amount: u64,
+        refresh_amount: u64,
+        percentage: u8,
+        fee_type: u8, // Type: FeeType- Anchor does not yet provide mappings for enums
+        mint: Option<Pubkey>,
+        discount: Option<FeeDiscount>,
+        routing: FeeRouting,
+    ) -> Result<()> {
+        // derived passes of an older version are accepted (see migrate_derived_pass)
+        load_derived_pass(&ctx.accounts.derived_pass)?;
+        validate_fee_override_routing(&routing)?;
+        create_or_update_fee(
+            ctx.accounts.fee.borrow_mut(),
+            issue_amount,
+            refresh_amount,
+            percentage,
+            fee_type,
+            mint,
+            discount,
+            routing,
+            // an override applies to a single derived pass, so is never shared by a batch
+            false,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_fee_override(
+        ctx: Context<UpdateFeeOverride>,
+        issue_amount: u64,
+        refresh_amount: u64,
+        percentage: u8,
+        fee_type: u8, // Type: FeeType- Anchor does not yet provide mappings for enums
+        mint: Option<Pubkey>,
+        discount: Option<FeeDiscount>,
+        routing: FeeRouting,
+    ) -> Result<()> {
+        // derived passes of an older version are accepted (see migrate_derived_pass)
+        load_derived_pass(&ctx.accounts.derived_pass)?;
+        validate_fee_override_routing(&routing)?;
+        create_or_update_fee(
+            ctx.accounts.fee.borrow_mut(),
+            issue_amount,
+            refresh_amount,
+            percentage,
+            fee_type,
+            mint,
+            discount,
+            routing,
+            // an override applies to a single derived pass, so is never shared by a batch
+            false,
+        )
+    }
+
+    pub fn remove_fee_override(_ctx: Context<RemoveFeeOverride>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Schedule a change to the amounts of a fee override (see create_fee_override),
+    /// taking effect at the effective time. Replaces any change already pending.
+    pub fn schedule_fee_override_update(
+        ctx: Context<ScheduleFeeOverrideUpdate>,
+        issue_amount: u64,
+        refresh_amount: u64,
+        percentage: u8,
+        effective_time: UnixTimestamp,
+    ) -> Result<()> {
+        ctx.accounts.fee.schedule(
+            PendingFee {
+                issue_amount,
+                refresh_amount,
+                percentage,
+                effective_time,
+            },
+            Clock::get()?.unix_timestamp,
+        )
+    }
+
+    /// Cancel a pending change to a fee override that has not yet taken effect.
+    pub fn cancel_pending_fee_override(ctx: Context<CancelPendingFeeOverride>) -> Result<()> {
+        ctx.accounts
+            .fee
+            .cancel_pending(Clock::get()?.unix_timestamp)
+    }
+
+    /// Set a default fee for all gatekeepers on a gatekeeper network, applied to the passes of any gatekeeper
+    /// that has not set a fee of its own. Must be signed by the gatekeeper network.
+    /// The fee is paid to the gatekeeper that issued the component pass, or according to the routing of the fee.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_network_fee(
+        ctx: Context<CreateNetworkFee>,
+        issue_amount: u64,
+        refresh_amount: u64,
+        percentage: u8,
+        fee_type: u8, // Type: FeeType- Anchor does not yet provide mappings for enums
+        mint: Option<Pubkey>,
+        discount: Option<FeeDiscount>,
+        routing: FeeRouting,
+        batch_discount: bool,
+    ) -> Result<()> {
+        validate_network_fee_routing(&routing)?;
+        create_or_update_fee(
+            ctx.accounts.fee.borrow_mut(),
+            issue_amount,
+            refresh_amount,
+            percentage,
+            fee_type,
+            mint,
+            discount,
+            routing,
+            batch_discount,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_network_fee(
+        ctx: Context<UpdateNetworkFee>,
+        issue_amount: u64,
+        refresh_amount: u64,
+        percentage: u8,
+        fee_type: u8, // Type: FeeType- Anchor does not yet provide mappings for enums
+        mint: Option<Pubkey>,
+        discount: Option<FeeDiscount>,
+        routing: FeeRouting,
+        batch_discount: bool,
+    ) -> Result<()> {
+        validate_network_fee_routing(&routing)?;
+        create_or_update_fee(
+            ctx.accounts.fee.borrow_mut(),
+            issue_amount,
+            refresh_amount,
+            percentage,
+            fee_type,
+            mint,
+            discount,
+            routing,
+            batch_discount,
+        )
+    }
+
+    pub fn remove_network_fee(_ctx: Context<RemoveNetworkFee>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Schedule a change to the amounts of the gatekeeper network's default fee (see create_network_fee),
+    /// taking effect at the effective time. Replaces any change already pending.
+    pub fn schedule_network_fee_update(
+        ctx: Context<ScheduleNetworkFeeUpdate>,
+        issue_amount: u64,
+        refresh_amount: u64,
+        percentage: u8,
+        effective_time: UnixTimestamp,
+    ) -> Result<()> {
+        ctx.accounts.fee.schedule(
+            PendingFee {
+                issue_amount,
+                refresh_amount,
+                percentage,
+                effective_time,
+            },
+            Clock::get()?.unix_timestamp,
+        )
+    }
+
+    /// Cancel a pending change to the gatekeeper network's default fee that has not yet taken effect.
+    pub fn cancel_pending_network_fee(ctx: Context<CancelPendingNetworkFee>) -> Result<()> {
+        ctx.accounts
+            .fee
+            .cancel_pending(Clock::get()?.unix_timestamp)
+    }
+
+    /// Create the vault that escrowed fees for a gatekeeper and gatekeeper network accrue in.
+    /// Must be called before fees are escrowed (see FeeRouting).
+    pub fn create_fee_vault(ctx: Context<CreateFeeVault>) -> Result<()> {
+        ctx.accounts.fee_vault.version = 0;
+        ctx.accounts.fee_vault.bump = *ctx.bumps.get("fee_vault").unwrap();
+        ctx.accounts.fee_vault.gatekeeper = *ctx.accounts.authority.key;
+        ctx.accounts.fee_vault.gatekeeper_network = *ctx.accounts.gatekeeper_network.key;
+        Ok(())
+    }
+
+    /// Withdraw the fees that have accrued in the fee vault to the gatekeeper, or its treasury or split recipients
+    pub fn claim_fees<'info>(ctx: Context<'_, '_, '_, 'info, ClaimFees<'info>>) -> Result<()> {
+        let destinations = get_fee_destinations(
+            &ctx.accounts.fee.to_account_info(),
+            ctx.accounts.authority.key,
+        )?;
+        // the destination account is the treasury or gatekeeper, or the first split recipient,
+        // in which case the other split recipients are passed in the remaining accounts
+        let destination_accounts: Vec<AccountInfo<'info>> =
+            std::iter::once(ctx.accounts.destination.to_account_info())
+                .chain(ctx.remaining_accounts.iter().cloned())
+                .collect();
+
+        let amount = claim_from_fee_vault(
+            &ctx.accounts.fee_vault.to_account_info(),
+            &destinations,
+            &destination_accounts,
+        )?;
+
+        emit!(FeesClaimed {
+            gatekeeper: ctx.accounts.authority.key(),
+            gatekeeper_network: ctx.accounts.gatekeeper_network.key(),
+            destination: ctx.accounts.destination.key(),
+            amount,
+        });
+
+        Ok(())
+    }
+
+    pub fn link_wallet(ctx: Context<LinkWallet>) -> Result<()> {
+        ctx.accounts.wallet_link.version = 0;
+        ctx.accounts.wallet_link.bump = *ctx.bumps.get("wallet_link").unwrap();
+        ctx.accounts.wallet_link.source_wallet = *ctx.accounts.source_wallet.key;
+        ctx.accounts.wallet_link.linked_wallet = *ctx.accounts.linked_wallet.key;
+        Ok(())
+    }
+
+    pub fn unlink_wallet(_ctx: Context<UnlinkWallet>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Create the statistics account of a derived pass created before statistics were added.
+    /// Can be called by anyone, with the payer funding the rent. Activity before this is not counted.
+    pub fn create_derived_pass_stats(ctx: Context<CreateDerivedPassStats>) -> Result<()> {
+        // derived passes of an older version are accepted (see migrate_derived_pass)
+        load_derived_pass(&ctx.accounts.derived_pass)?;
+
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.stats.version = 0;
+        ctx.accounts.stats.bump = *ctx.bumps.get("stats").unwrap();
+        ctx.accounts.stats.derived_pass = ctx.accounts.derived_pass.key();
+        ctx.accounts.stats.created_at = now;
+        ctx.accounts.stats.last_activity = now;
+        Ok(())
+    }
+
+    /// Freeze a derived pass held by a recipient, e.g. while a misbehaving wallet is investigated.
+    /// A frozen pass is not valid until it is unfrozen. Can only be called by the derived pass authority.
+    pub fn freeze_pass(ctx: Context<SetPassState>) -> Result<()> {
+        let (derived_pass, _) = ctx.accounts.load_derived_pass_and_token()?;
+        freeze_derived_pass(ctx.accounts.to_gateway_token_state_params(&[
+            GATEKEEPER_SEED,
+            &derived_pass.authority.to_bytes(),
+            &[derived_pass.gatekeeper_bump],
+        ]))
+    }
+
+    /// Unfreeze a derived pass frozen with freeze_pass. Can only be called by the derived pass authority.
+    pub fn unfreeze_pass(ctx: Context<SetPassState>) -> Result<()> {
+        let (derived_pass, _) = ctx.accounts.load_derived_pass_and_token()?;
+        unfreeze_derived_pass(ctx.accounts.to_gateway_token_state_params(&[
+            GATEKEEPER_SEED,
+            &derived_pass.authority.to_bytes(),
+            &[derived_pass.gatekeeper_bump],
+        ]))
+    }
+
+    /// Permanently revoke a derived pass held by a recipient, e.g. in a case of fraud.
+    /// The revoked gateway token remains, so the derived pass cannot be issued to the same wallet again.
+    /// The reason code is chosen by the derived pass authority, and emitted in a PassRevoked event.
+    /// Can only be called by the derived pass authority.
+    pub fn revoke_pass(ctx: Context<SetPassState>, reason: u8) -> Result<()> {
+        let (derived_pass, gateway_token) = ctx.accounts.load_derived_pass_and_token()?;
+        revoke_derived_pass(ctx.accounts.to_gateway_token_state_params(&[
+            GATEKEEPER_SEED,
+            &derived_pass.authority.to_bytes(),
+            &[derived_pass.gatekeeper_bump],
+        ]))?;
+
+        emit!(PassRevoked {
+            derived_pass: ctx.accounts.derived_pass.key(),
+            gateway_token: ctx.accounts.gateway_token.key(),
+            owner: gateway_token.owner_wallet,
+            reason,
+        });
+
         Ok(())
     }
+
+    /// Upgrade a derived pass of an older version to the current layout, resizing it to include the reserved space.
+    /// New properties take their default values. Can be called by anyone, with the payer funding any extra rent.
+    pub fn migrate_derived_pass(ctx: Context<MigrateDerivedPass>) -> Result<()> {
+        let derived_pass_account = ctx.accounts.derived_pass.to_account_info();
+        let mut derived_pass = load_derived_pass(&derived_pass_account)?;
+        require!(
+            derived_pass.version < DERIVED_PASS_VERSION,
+            ErrorCode::AccountAlreadyMigrated
+        );
+
+        derived_pass.version = DERIVED_PASS_VERSION;
+        let space = DerivedPass::get_space(derived_pass.source_gkns.len());
+        write_migrated_account(
+            &derived_pass_account,
+            &derived_pass,
+            space,
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+        )
+    }
+
+    /// Upgrade a fee (including a fee override or network fee) of an older version to the current layout,
+    /// resizing it to include the reserved space. Can be called by anyone, with the payer funding any extra rent.
+    pub fn migrate_fee(ctx: Context<MigrateFee>) -> Result<()> {
+        let fee_account = ctx.accounts.fee.to_account_info();
+        let mut fee = load_fee(&fee_account)?;
+        require!(fee.version < FEE_VERSION, ErrorCode::AccountAlreadyMigrated);
+
+        fee.version = FEE_VERSION;
+        write_migrated_account(
+            &fee_account,
+            &fee,
+            Fee::get_space(),
+            &ctx.accounts.payer,
+            &ctx.accounts.system_program,
+        )
+    }
 }
 
 #[derive(Clone, Copy, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
@@ -227,6 +909,151 @@ pub struct DerivedPassProperties {
     /// If false, the derived pass cannot be refreshed.
     /// Use this for "single-use" passes.
     pub refresh_disabled: bool,
+    /// If true, component passes held by a wallet linked to the recipient (see WalletLink)
+    /// can be used to issue or refresh the derived pass.
+    pub allow_linked_wallets: bool,
+    /// Limits on the component pass fees paid on issue or refresh
+    pub fee_caps: Option<FeeCaps>,
+    /// The maximum number of times the derived pass can be issued, e.g. for a limited campaign
+    pub max_supply: Option<u64>,
+    /// The maximum number of times the derived pass can be issued in a window of time,
+    /// e.g. to protect against bursts of sybil issuances
+    pub max_issues_per_window: Option<IssueQuota>,
+    /// The minimum time in seconds between refreshes of a recipient's derived pass,
+    /// counted from its last issue or refresh
+    pub min_refresh_interval: Option<i64>,
+    /// The maximum number of times a recipient's derived pass can be refreshed after each issue
+    pub max_refreshes: Option<u32>,
+    /// If true, each component pass must have an expiry time
+    pub require_component_expiry: bool,
+    /// The minimum time in seconds that each component pass with an expiry time must remain valid for,
+    /// on issue or refresh of the derived pass
+    pub min_component_validity: Option<i64>,
+    /// The time from which the derived pass can be issued, e.g. the start of a campaign
+    pub issue_not_before: Option<UnixTimestamp>,
+    /// The time after which the derived pass can no longer be issued, e.g. the end of a campaign
+    pub issue_not_after: Option<UnixTimestamp>,
+    /// If true, the derived pass can only be refreshed between issue_not_before and issue_not_after too
+    pub issue_window_applies_to_refresh: bool,
+    /// A fixed time at which the derived pass expires, e.g. the end of a season.
+    /// If expire_duration is also set, the derived pass expires at the earlier of the two.
+    pub expire_at: Option<UnixTimestamp>,
+    /// If set, the derived pass can only be refreshed in this many seconds before it expires, or after it has expired,
+    /// so that it cannot be kept alive indefinitely by refreshing it early
+    pub refresh_window: Option<i64>,
+    /// How the expiry time of the derived pass is extended on refresh
+    pub refresh_extension: RefreshExtension,
+}
+impl DerivedPassProperties {
+    pub fn get_space() -> usize {
+        1 + U64_SIZE // expire_duration: Optional marker adds 1 byte
+        + (3 * U8_SIZE)
+        + 1 + FeeCaps::get_space() // fee_caps: Optional marker adds 1 byte
+        + 1 + U64_SIZE // max_supply: Optional marker adds 1 byte
+        + 1 + IssueQuota::get_space() // max_issues_per_window: Optional marker adds 1 byte
+        + 1 + U64_SIZE // min_refresh_interval: Optional marker adds 1 byte
+        + 1 + U32_SIZE // max_refreshes: Optional marker adds 1 byte
+        + U8_SIZE // require_component_expiry
+        + 1 + U64_SIZE // min_component_validity: Optional marker adds 1 byte
+        + 2 * (1 + U64_SIZE) // issue_not_before, issue_not_after: Optional marker adds 1 byte
+        + U8_SIZE // issue_window_applies_to_refresh
+        + 1 + U64_SIZE // expire_at: Optional marker adds 1 byte
+        + 1 + U64_SIZE // refresh_window: Optional marker adds 1 byte
+        + 1 + U64_SIZE // refresh_extension: enum variant marker adds 1 byte
+    }
+
+    /// Check that the properties are consistent, when creating a derived pass
+    pub fn validate(&self) -> Result<()> {
+        if let (Some(not_before), Some(not_after)) = (self.issue_not_before, self.issue_not_after) {
+            require!(not_before < not_after, ErrorCode::InvalidIssueWindow);
+        }
+        if let Some(quota) = self.max_issues_per_window {
+            require!(quota.window_duration > 0, ErrorCode::InvalidIssueQuota);
+        }
+        if let RefreshExtension::FromExpiry { max_horizon } = self.refresh_extension {
+            require!(max_horizon > 0, ErrorCode::InvalidRefreshExtension);
+        }
+        Ok(())
+    }
+
+    /// Check that the time is within the issue window of the derived pass
+    pub fn check_issue_window(&self, now: UnixTimestamp) -> Result<()> {
+        if let Some(not_before) = self.issue_not_before {
+            require!(now >= not_before, ErrorCode::IssueWindowNotOpen);
+        }
+        if let Some(not_after) = self.issue_not_after {
+            require!(now <= not_after, ErrorCode::IssueWindowClosed);
+        }
+        Ok(())
+    }
+
+    /// If true, the refreshes of each recipient are limited, and tracked in a RecipientState account
+    pub fn has_refresh_limits(&self) -> bool {
+        self.min_refresh_interval.is_some() || self.max_refreshes.is_some()
+    }
+}
+
+/// How the expiry time of a derived pass with an expire_duration is extended on refresh
+#[derive(Clone, Copy, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub enum RefreshExtension {
+    /// The derived pass expires expire_duration after the refresh
+    FromNow,
+    /// The derived pass expires expire_duration after its current expiry time (or after the refresh, if it has expired),
+    /// so that refreshing early does not waste the time already paid for.
+    /// The expiry time is capped at max_horizon seconds after the refresh, which must be positive.
+    FromExpiry { max_horizon: i64 },
+}
+
+/// A limit on the number of issuances of a derived pass in a fixed window of time.
+/// A new window starts with the first issuance after the previous window has ended.
+#[derive(Clone, Copy, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub struct IssueQuota {
+    /// The length of the window in seconds
+    pub window_duration: i64,
+    /// The maximum number of issuances in each window
+    pub max_issues: u32,
+}
+impl IssueQuota {
+    pub fn get_space() -> usize {
+        U64_SIZE + U32_SIZE
+    }
+}
+
+/// Counts of the issuances of a derived pass, used to enforce its max_supply and max_issues_per_window
+#[derive(Clone, Copy, Debug, Default, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub struct IssuanceCounters {
+    /// The number of times the derived pass has been issued
+    pub issued: u64,
+    /// The start of the current issue window
+    pub window_start: UnixTimestamp,
+    /// The number of times the derived pass has been issued in the current issue window
+    pub window_issued: u32,
+}
+impl IssuanceCounters {
+    pub fn get_space() -> usize {
+        U64_SIZE + U64_SIZE + U32_SIZE
+    }
+}
+
+/// Limits on the fees paid to the component pass gatekeepers on issue or refresh of a derived pass,
+/// so that the derived pass authority can guarantee a price, whatever fees the gatekeepers set.
+/// The caps apply after any discounts and vouchers. An issue or refresh exceeding a cap is rejected:
+/// the fees are set by the gatekeepers, so the caps of a derived pass cannot reduce them.
+#[derive(Clone, Copy, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub struct FeeCaps {
+    /// The maximum total fee for issuing the derived pass
+    pub max_issue_total: Option<u64>,
+    /// The maximum total fee for refreshing the derived pass
+    pub max_refresh_total: Option<u64>,
+    /// The maximum fee for each component pass when issuing the derived pass
+    pub max_issue_per_component: Option<u64>,
+    /// The maximum fee for each component pass when refreshing the derived pass
+    pub max_refresh_per_component: Option<u64>,
+}
+impl FeeCaps {
+    pub fn get_space() -> usize {
+        4 * (1 + U64_SIZE) // each cap: Optional marker adds 1 byte
+    }
 }
 
 #[account]
@@ -235,8 +1062,51 @@ pub struct DerivedPass {
     pub authority: Pubkey,
     pub gatekeeper_bump: u8,
     pub source_gkns: Vec<Pubkey>,
+    pub counters: IssuanceCounters,
+    // the properties are last, so that new properties can be added in the reserved space
     pub properties: DerivedPassProperties,
 }
+impl DerivedPass {
+    pub fn get_space(source_gkn_count: usize) -> usize {
+        DISCRIMINATOR_SIZE + U8_SIZE + PUBKEY_SIZE + U8_SIZE
+        + 4 + (source_gkn_count * PUBKEY_SIZE) // source_gkns: Vec length prefix adds 4 bytes
+        + IssuanceCounters::get_space()
+        + DerivedPassProperties::get_space()
+        + RESERVED_SPACE
+    }
+
+    /// Count an issuance of the derived pass, checking that it is within the issue window, max supply and issue quota
+    pub fn record_issue(&mut self, now: UnixTimestamp) -> Result<()> {
+        self.properties.check_issue_window(now)?;
+
+        if let Some(max_supply) = self.properties.max_supply {
+            require!(
+                self.counters.issued < max_supply,
+                ErrorCode::MaxSupplyReached
+            );
+        }
+
+        if let Some(quota) = self.properties.max_issues_per_window {
+            if now
+                >= self
+                    .counters
+                    .window_start
+                    .saturating_add(quota.window_duration)
+            {
+                self.counters.window_start = now;
+                self.counters.window_issued = 0;
+            }
+            require!(
+                self.counters.window_issued < quota.max_issues,
+                ErrorCode::IssueQuotaExceeded
+            );
+            self.counters.window_issued += 1;
+        }
+
+        self.counters.issued = self.counters.issued.saturating_add(1);
+        Ok(())
+    }
+}
 
 #[derive(Clone, Debug, AnchorDeserialize, AnchorSerialize, FromPrimitive)]
 pub enum FeeType {
@@ -249,6 +1119,56 @@ impl Default for FeeType {
     }
 }
 
+/// A discount on a fee, for recipients holding a pass on a given gatekeeper network (e.g. a loyalty pass)
+#[derive(Clone, Copy, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub struct FeeDiscount {
+    /// The gatekeeper network of the pass that the recipient must hold to receive the discount
+    pub gatekeeper_network: Pubkey,
+    /// The percentage (0-100) to deduct from the fee
+    pub percentage: u8,
+    /// A fixed amount to deduct from the fee, after the percentage discount is applied
+    pub amount: u64,
+}
+impl FeeDiscount {
+    pub fn get_space() -> usize {
+        PUBKEY_SIZE + U8_SIZE + U64_SIZE
+    }
+}
+
+/// Where the fees of a gatekeeper are paid
+#[derive(Clone, Debug, Default, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub struct FeeRouting {
+    /// If true, fees accrue in the fee vault of the gatekeeper and gatekeeper network (see FeeVault),
+    /// rather than being transferred to the gatekeeper on each issue or refresh.
+    pub escrow: bool,
+    /// If set, fees are paid to (or claimed from the fee vault to) this address instead of the gatekeeper,
+    /// e.g. a cold wallet or multisig.
+    pub treasury: Option<Pubkey>,
+    /// If not empty, fees are split between these recipients instead of being paid to the gatekeeper.
+    /// Cannot be combined with a treasury.
+    pub splits: Vec<FeeSplit>,
+}
+impl FeeRouting {
+    pub fn get_space() -> usize {
+        U8_SIZE + PUBKEY_SIZE + 1 // treasury: Optional marker adds 1 byte
+        + 4 + (MAX_FEE_SPLITS * FeeSplit::get_space()) // splits: Vec length prefix adds 4 bytes
+    }
+}
+
+/// A share of a fee, paid to a recipient other than the gatekeeper (e.g. a KYC vendor).
+/// The shares of a fee must sum to 100% (10,000 basis points).
+/// Any remainder from rounding is paid to the first recipient.
+#[derive(Clone, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub struct FeeSplit {
+    pub recipient: Pubkey,
+    pub basis_points: u16,
+}
+impl FeeSplit {
+    pub fn get_space() -> usize {
+        PUBKEY_SIZE + U16_SIZE
+    }
+}
+
 #[account]
 pub struct Fee {
     pub version: u8,
@@ -257,18 +1177,249 @@ pub struct Fee {
     pub issue_amount: u64,
     pub refresh_amount: u64,
     pub mint: Option<Pubkey>,
+    pub discount: Option<FeeDiscount>,
+    pub routing: FeeRouting,
+    /// A fee change scheduled with schedule_fee_update, not yet applied
+    pub pending: Option<PendingFee>,
+    /// If true, the fee is charged once per component pass when several derived passes are issued at once
+    /// with issue_many, rather than once for each derived pass the component pass is used for.
+    pub batch_discount: bool,
 }
 impl Fee {
     pub fn get_space() -> usize {
         DISCRIMINATOR_SIZE + (3 * U8_SIZE) + U64_SIZE + U64_SIZE + PUBKEY_SIZE + 1
         // mint: Optional marker adds 1 byte
+        + FeeDiscount::get_space() + 1
+        // discount: Optional marker adds 1 byte
+        + FeeRouting::get_space()
+        + PendingFee::get_space() + 1 // pending: Optional marker adds 1 byte
+        + U8_SIZE // batch_discount
+        + RESERVED_SPACE
     }
-}
 
-#[derive(Accounts)]
-#[instruction(source_gkns: Vec<Pubkey>, size: u8, gatekeeper_bump: u8, properties: DerivedPassProperties)]
+    /// Schedule a change to the fee amounts, replacing any change already pending.
+    /// The effective time must be in the future.
+    pub fn schedule(&mut self, pending: PendingFee, now: UnixTimestamp) -> Result<()> {
+        require!(
+            pending.effective_time > now,
+            ErrorCode::InvalidEffectiveTime
+        );
+
+        self.pending = Some(pending);
+        Ok(())
+    }
+
+    /// Cancel the pending fee change, which must not yet have taken effect.
+    pub fn cancel_pending(&mut self, now: UnixTimestamp) -> Result<()> {
+        let pending = self
+            .pending
+            .ok_or_else(|| error!(ErrorCode::NoPendingFee))?;
+        require!(
+            pending.effective_time > now,
+            ErrorCode::PendingFeeAlreadyEffective
+        );
+
+        self.pending = None;
+        Ok(())
+    }
+
+    /// Apply the pending fee change, if it has taken effect by the given time.
+    /// Returns true if a change was applied.
+    pub fn apply_pending(&mut self, now: UnixTimestamp) -> bool {
+        match self.pending {
+            Some(pending) if pending.effective_time <= now => {
+                self.issue_amount = pending.issue_amount;
+                self.refresh_amount = pending.refresh_amount;
+                self.percentage = pending.percentage;
+                self.pending = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A fee change scheduled by a gatekeeper, taking effect at the effective time.
+/// Until then, the current fee is charged, so that integrators can show users stable prices.
+#[derive(Clone, Copy, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub struct PendingFee {
+    pub issue_amount: u64,
+    pub refresh_amount: u64,
+    pub percentage: u8,
+    pub effective_time: UnixTimestamp,
+}
+impl PendingFee {
+    pub fn get_space() -> usize {
+        U64_SIZE + U64_SIZE + U8_SIZE + U64_SIZE
+    }
+}
+
+/// A program-owned account in which the escrowed fees of a gatekeeper on a gatekeeper network accrue,
+/// until claimed by the gatekeeper using claim_fees.
+#[account]
+pub struct FeeVault {
+    pub version: u8,
+    pub bump: u8,
+    pub gatekeeper: Pubkey,
+    pub gatekeeper_network: Pubkey,
+}
+impl FeeVault {
+    pub fn get_space() -> usize {
+        DISCRIMINATOR_SIZE + (2 * U8_SIZE) + (2 * PUBKEY_SIZE)
+    }
+}
+
+/// A link between two wallets, signed by both, allowing component passes held by the source wallet
+/// to be used to issue derived passes to the linked wallet.
+/// Typically, the source wallet is a "cold" wallet holding KYC passes, and the linked wallet is a "hot"
+/// wallet used for trading.
+#[account]
+pub struct WalletLink {
+    pub version: u8,
+    pub bump: u8,
+    /// The wallet holding the component passes
+    pub source_wallet: Pubkey,
+    /// The wallet that may receive derived passes on the basis of the source wallet's component passes
+    pub linked_wallet: Pubkey,
+}
+impl WalletLink {
+    pub fn get_space() -> usize {
+        DISCRIMINATOR_SIZE + (2 * U8_SIZE) + (2 * PUBKEY_SIZE)
+    }
+}
+
+/// Usage statistics of a derived pass, updated on issue and refresh.
+/// Fees are the totals paid to the component pass gatekeepers, in lamports.
+#[account]
+pub struct DerivedPassStats {
+    pub version: u8,
+    pub bump: u8,
+    pub derived_pass: Pubkey,
+    /// The number of times the derived pass has been issued
+    pub issued: u64,
+    /// The number of times the derived pass has been refreshed
+    pub refreshed: u64,
+    /// The total fees paid on issue
+    pub issue_fees_paid: u64,
+    /// The total fees paid on refresh
+    pub refresh_fees_paid: u64,
+    /// The time the statistics account was created
+    pub created_at: UnixTimestamp,
+    /// The time of the last issue or refresh
+    pub last_activity: UnixTimestamp,
+}
+impl DerivedPassStats {
+    pub fn get_space() -> usize {
+        DISCRIMINATOR_SIZE + (2 * U8_SIZE) + PUBKEY_SIZE
+        + (4 * U64_SIZE) // issue and refresh counts and fee totals
+        + (2 * U64_SIZE) // timestamps
+        + RESERVED_SPACE
+    }
+}
+
+/// The state of a derived pass held by a recipient, used to enforce the refresh limits of the derived pass.
+/// Created on issue, for derived passes with refresh limits only.
+#[account]
+pub struct RecipientState {
+    pub version: u8,
+    pub bump: u8,
+    pub derived_pass: Pubkey,
+    pub recipient: Pubkey,
+    /// The time the derived pass was last issued to the recipient
+    pub last_issue: UnixTimestamp,
+    /// The time the derived pass was last refreshed, if since the last issue
+    pub last_refresh: Option<UnixTimestamp>,
+    /// The number of refreshes since the last issue
+    pub refresh_count: u32,
+}
+impl RecipientState {
+    pub fn get_space() -> usize {
+        DISCRIMINATOR_SIZE + (2 * U8_SIZE) + (2 * PUBKEY_SIZE)
+        + U64_SIZE // last_issue
+        + 1 + U64_SIZE // last_refresh: Optional marker adds 1 byte
+        + U32_SIZE
+        + RESERVED_SPACE
+    }
+}
+
+/// The action for which fees are paid
+#[derive(Clone, Copy, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub enum Action {
+    Issue,
+    Refresh,
+}
+
+/// An entry in the breakdown of fees paid on issue or refresh of a derived pass.
+/// There is one entry per gatekeeper and gatekeeper network, in the order the component passes were passed in.
+#[derive(Clone, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub struct FeePayment {
+    pub gatekeeper: Pubkey,
+    pub gatekeeper_network: Pubkey,
+    pub action: Action,
+    pub amount: u64,
+    pub mint: Option<Pubkey>,
+}
+
+/// Emitted when the derived pass authority revokes a derived pass held by a recipient
+#[event]
+pub struct PassRevoked {
+    pub derived_pass: Pubkey,
+    pub gateway_token: Pubkey,
+    /// The wallet holding the revoked pass
+    pub owner: Pubkey,
+    /// A reason code chosen by the derived pass authority
+    pub reason: u8,
+}
+
+/// Emitted when a gatekeeper claims the fees accrued in its fee vault
+#[event]
+pub struct FeesClaimed {
+    pub gatekeeper: Pubkey,
+    pub gatekeeper_network: Pubkey,
+    pub destination: Pubkey,
+    pub amount: u64,
+}
+
+/// A one-off voucher reducing or waiving a gatekeeper's fees for a single issue or refresh of a derived pass.
+/// The voucher is signed by the gatekeeper whose fees it reduces. The signature is checked
+/// by an ed25519 program instruction preceding the issue or refresh instruction in the same transaction.
+/// Each voucher can be used once only, after which a consumed-voucher PDA exists for it.
+#[derive(Clone, Debug, AnchorDeserialize, AnchorSerialize, PartialEq)]
+pub struct FeeVoucher {
+    /// An identifier chosen by the signer, unique among the vouchers it signs
+    pub id: u64,
+    /// The signer of the voucher, which must be the gatekeeper
+    pub signer: Pubkey,
+    /// The wallet that may use the voucher
+    pub recipient: Pubkey,
+    /// The derived pass that the voucher may be used for
+    pub derived_pass: Pubkey,
+    /// The action that the voucher may be used for
+    pub action: Action,
+    /// The gatekeeper whose fees are reduced
+    pub gatekeeper: Pubkey,
+    /// If set, only the gatekeeper's fee on this gatekeeper network is reduced
+    pub gatekeeper_network: Option<Pubkey>,
+    /// The amount to deduct from each matching fee. Use u64::MAX to waive the fees entirely
+    pub amount: u64,
+    /// The time after which the voucher can no longer be used
+    pub expire_time: Option<UnixTimestamp>,
+}
+
+/// Emitted when fees are paid on issue or refresh of one or more derived passes
+#[event]
+pub struct FeesPaid {
+    pub recipient: Pubkey,
+    pub derived_passes: Vec<Pubkey>,
+    /// The fee breakdown, with the breakdown of each derived pass in turn if several are issued at once
+    pub fees: Vec<FeePayment>,
+}
+
+#[derive(Accounts)]
+#[instruction(source_gkns: Vec<Pubkey>, size: u8, gatekeeper_bump: u8, properties: DerivedPassProperties)]
 pub struct Initialize<'info> {
-    #[account(init, payer = authority, space = size.into())]
+    // the size argument is ignored, and kept for compatibility with existing clients
+    #[account(init, payer = authority, space = DerivedPass::get_space(source_gkns.len()))]
     derived_pass: Account<'info, DerivedPass>,
     #[account(mut)]
     authority: Signer<'info>, // this is the "gatekeeper network"
@@ -289,16 +1440,25 @@ pub struct Initialize<'info> {
     //  Must not exist i.e. owned by the system program and have size = 0
     /// CHECK: Size and owner is checked in the program - the derivation is checked in the gateway program.
     derived_gatekeeper_account: UncheckedAccount<'info>,
+    #[account(
+  init,
+  payer = authority,
+  space = DerivedPassStats::get_space(),
+  seeds = [STATS_SEED.as_ref(), derived_pass.key().to_bytes().as_ref()],
+  bump
+  )]
+    stats: Account<'info, DerivedPassStats>,
     gateway_program: Program<'info, Gateway>,
     rent: Sysvar<'info, Rent>,
     system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-#[instruction(fee_bumps: Vec<u8>)]
+#[instruction(fee_bumps: Vec<u8>, component_account_counts: Vec<u8>, voucher: Option<FeeVoucher>, stats_bump: u8)]
 pub struct Issue<'info> {
-    #[account()]
-    derived_pass: Account<'info, DerivedPass>,
+    /// CHECK: Loaded in the program, accepting older versions (see migrate_derived_pass)
+    #[account(mut, owner = id())]
+    derived_pass: UncheckedAccount<'info>,
     #[account(mut)]
     recipient: Signer<'info>,
     #[account(mut)]
@@ -315,22 +1475,40 @@ pub struct Issue<'info> {
     /// The account linking the derived gatekeeper to the derived_pass gatekeeper network
     /// CHECK: Checked in the CPI to the Gateway program
     derived_gatekeeper_account: UncheckedAccount<'info>,
+    /// CHECK: The statistics account of the derived pass. Activity is not recorded if it has not been created (see create_derived_pass_stats)
+    #[account(mut, seeds = [STATS_SEED, &derived_pass.key().to_bytes()], bump = stats_bump)]
+    stats: UncheckedAccount<'info>,
+    /// CHECK: The state of the derived pass held by the recipient, used if the derived pass has refresh limits.
+    /// Created on issue if needed (see RecipientState)
+    #[account(mut, seeds = [RECIPIENT_STATE_SEED, &derived_pass.key().to_bytes(), &recipient.key.to_bytes()], bump)]
+    recipient_state: UncheckedAccount<'info>,
     gateway_program: Program<'info, Gateway>,
     rent: Sysvar<'info, Rent>,
     system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-#[instruction(fee_bumps: Vec<u8>)]
+#[instruction(derived_pass_count: u8, fee_bumps: Vec<u8>, component_account_counts: Vec<u8>, stats_bumps: Vec<u8>)]
+pub struct IssueMany<'info> {
+    #[account(mut)]
+    recipient: Signer<'info>,
+    gateway_program: Program<'info, Gateway>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(fee_bumps: Vec<u8>, component_account_counts: Vec<u8>, voucher: Option<FeeVoucher>, stats_bump: u8)]
 pub struct Refresh<'info> {
-    #[account()]
-    derived_pass: Account<'info, DerivedPass>,
+    /// CHECK: Loaded in the program, accepting older versions (see migrate_derived_pass)
+    #[account(owner = id())]
+    derived_pass: UncheckedAccount<'info>,
     #[account(mut)]
     recipient: Signer<'info>,
     #[account(mut)]
     /// The gateway token to be refreshed
-    ///  Must be a valid gateway token owned by the recipient
-    /// CHECK: the derivation is checked in the gateway program.
+    ///  Must be a valid gateway token in the derived_pass gatekeeper network, owned by the recipient
+    /// CHECK: Checked in the program - the derivation is checked in the gateway program.
     gateway_token: UncheckedAccount<'info>,
     #[account()]
     /// A PDA representing the gatekeeper.
@@ -340,13 +1518,20 @@ pub struct Refresh<'info> {
     /// The account linking the derived gatekeeper to the derived_pass gatekeeper network
     /// CHECK: Checked in the CPI to the Gateway program
     derived_gatekeeper_account: UncheckedAccount<'info>,
+    /// CHECK: The statistics account of the derived pass. Activity is not recorded if it has not been created (see create_derived_pass_stats)
+    #[account(mut, seeds = [STATS_SEED, &derived_pass.key().to_bytes()], bump = stats_bump)]
+    stats: UncheckedAccount<'info>,
+    /// CHECK: The state of the derived pass held by the recipient, used if the derived pass has refresh limits.
+    /// Created on issue if needed (see RecipientState)
+    #[account(mut, seeds = [RECIPIENT_STATE_SEED, &derived_pass.key().to_bytes(), &recipient.key.to_bytes()], bump)]
+    recipient_state: UncheckedAccount<'info>,
     gateway_program: Program<'info, Gateway>,
     rent: Sysvar<'info, Rent>,
     system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-#[instruction(issue_amount: u64, refresh_amount: u64, percentage: u8, fee_type: u8, mint: Option<Pubkey>)]
+#[instruction(issue_amount: u64, refresh_amount: u64, percentage: u8, fee_type: u8, mint: Option<Pubkey>, discount: Option<FeeDiscount>, routing: FeeRouting, batch_discount: bool)]
 pub struct CreateFee<'info> {
     #[account(
   init,
@@ -365,9 +1550,10 @@ pub struct CreateFee<'info> {
 }
 
 #[derive(Accounts)]
-#[instruction(issue_amount: u64, refresh_amount: u64, percentage: u8, fee_type: u8, mint: Option<Pubkey>)]
+#[instruction(issue_amount: u64, refresh_amount: u64, percentage: u8, fee_type: u8, mint: Option<Pubkey>, discount: Option<FeeDiscount>, routing: FeeRouting, batch_discount: bool)]
 pub struct UpdateFee<'info> {
-    #[account(mut, seeds = [FEE_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes()], bump)]
+    /// Fees of an older version must be migrated first (see migrate_fee), as the update may need the reserved space
+    #[account(mut, seeds = [FEE_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes()], bump, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
     fee: Account<'info, Fee>,
     #[account(mut)]
     authority: Signer<'info>, // the gatekeeper
@@ -387,6 +1573,324 @@ pub struct RemoveFee<'info> {
     gatekeeper_network: UncheckedAccount<'info>,
 }
 
+#[derive(Accounts)]
+pub struct ScheduleFeeUpdate<'info> {
+    #[account(mut, seeds = [FEE_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes()], bump, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
+    fee: Account<'info, Fee>,
+    authority: Signer<'info>, // the gatekeeper
+    /// CHECK: This can be any public key (in reality it should match a known gatekeeper network)
+    gatekeeper_network: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ApplyPendingFee<'info> {
+    #[account(mut, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
+    fee: Account<'info, Fee>,
+}
+
+#[derive(Accounts)]
+pub struct CancelPendingFee<'info> {
+    #[account(mut, seeds = [FEE_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes()], bump, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
+    fee: Account<'info, Fee>,
+    authority: Signer<'info>, // the gatekeeper
+    /// CHECK: This can be any public key (in reality it should match a known gatekeeper network)
+    gatekeeper_network: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(issue_amount: u64, refresh_amount: u64, percentage: u8, fee_type: u8, mint: Option<Pubkey>, discount: Option<FeeDiscount>, routing: FeeRouting)]
+pub struct CreateFeeOverride<'info> {
+    #[account(
+  init,
+  payer = authority,
+  space = Fee::get_space(),
+  seeds = [FEE_OVERRIDE_SEED.as_ref(), authority.key.to_bytes().as_ref(), gatekeeper_network.key.to_bytes().as_ref(), derived_pass.key().to_bytes().as_ref()],
+  bump
+  )]
+    fee: Account<'info, Fee>,
+    #[account(mut)]
+    authority: Signer<'info>, // the gatekeeper
+    /// CHECK: This can be any public key (in reality it should match a known gatekeeper network)
+    gatekeeper_network: UncheckedAccount<'info>,
+    /// The derived pass that the fee override applies to
+    /// CHECK: Loaded in the program, accepting older versions (see migrate_derived_pass)
+    #[account(owner = id())]
+    derived_pass: UncheckedAccount<'info>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(issue_amount: u64, refresh_amount: u64, percentage: u8, fee_type: u8, mint: Option<Pubkey>, discount: Option<FeeDiscount>, routing: FeeRouting)]
+pub struct UpdateFeeOverride<'info> {
+    #[account(mut, seeds = [FEE_OVERRIDE_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes(), &derived_pass.key().to_bytes()], bump, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
+    fee: Account<'info, Fee>,
+    #[account(mut)]
+    authority: Signer<'info>, // the gatekeeper
+    /// CHECK: This can be any public key (in reality it should match a known gatekeeper network)
+    gatekeeper_network: UncheckedAccount<'info>,
+    /// The derived pass that the fee override applies to
+    /// CHECK: Loaded in the program, accepting older versions (see migrate_derived_pass)
+    #[account(owner = id())]
+    derived_pass: UncheckedAccount<'info>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveFeeOverride<'info> {
+    #[account(mut, close = authority, seeds = [FEE_OVERRIDE_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes(), &derived_pass.key().to_bytes()], bump)]
+    fee: Account<'info, Fee>,
+    #[account(mut)]
+    authority: Signer<'info>, // the gatekeeper
+    /// CHECK: This can be any public key (in reality it should match a known gatekeeper network)
+    gatekeeper_network: UncheckedAccount<'info>,
+    /// The derived pass that the fee override applies to
+    /// CHECK: This can be any public key, so that overrides can be removed after the derived pass is closed
+    derived_pass: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ScheduleFeeOverrideUpdate<'info> {
+    #[account(mut, seeds = [FEE_OVERRIDE_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes(), &derived_pass.key().to_bytes()], bump, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
+    fee: Account<'info, Fee>,
+    authority: Signer<'info>, // the gatekeeper
+    /// CHECK: This can be any public key (in reality it should match a known gatekeeper network)
+    gatekeeper_network: UncheckedAccount<'info>,
+    /// The derived pass that the fee override applies to
+    /// CHECK: This can be any public key, as the fee override is derived from it
+    derived_pass: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelPendingFeeOverride<'info> {
+    #[account(mut, seeds = [FEE_OVERRIDE_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes(), &derived_pass.key().to_bytes()], bump, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
+    fee: Account<'info, Fee>,
+    authority: Signer<'info>, // the gatekeeper
+    /// CHECK: This can be any public key (in reality it should match a known gatekeeper network)
+    gatekeeper_network: UncheckedAccount<'info>,
+    /// The derived pass that the fee override applies to
+    /// CHECK: This can be any public key, as the fee override is derived from it
+    derived_pass: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(issue_amount: u64, refresh_amount: u64, percentage: u8, fee_type: u8, mint: Option<Pubkey>, discount: Option<FeeDiscount>, routing: FeeRouting, batch_discount: bool)]
+pub struct CreateNetworkFee<'info> {
+    #[account(
+  init,
+  payer = gatekeeper_network,
+  space = Fee::get_space(),
+  seeds = [NETWORK_FEE_SEED.as_ref(), gatekeeper_network.key.to_bytes().as_ref()],
+  bump
+  )]
+    fee: Account<'info, Fee>,
+    /// The gatekeeper network key is the authority of the network in the gateway program
+    #[account(mut)]
+    gatekeeper_network: Signer<'info>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(issue_amount: u64, refresh_amount: u64, percentage: u8, fee_type: u8, mint: Option<Pubkey>, discount: Option<FeeDiscount>, routing: FeeRouting, batch_discount: bool)]
+pub struct UpdateNetworkFee<'info> {
+    #[account(mut, seeds = [NETWORK_FEE_SEED, &gatekeeper_network.key.to_bytes()], bump, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
+    fee: Account<'info, Fee>,
+    #[account(mut)]
+    gatekeeper_network: Signer<'info>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveNetworkFee<'info> {
+    #[account(mut, close = gatekeeper_network, seeds = [NETWORK_FEE_SEED, &gatekeeper_network.key.to_bytes()], bump)]
+    fee: Account<'info, Fee>,
+    #[account(mut)]
+    gatekeeper_network: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ScheduleNetworkFeeUpdate<'info> {
+    #[account(mut, seeds = [NETWORK_FEE_SEED, &gatekeeper_network.key.to_bytes()], bump, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
+    fee: Account<'info, Fee>,
+    gatekeeper_network: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelPendingNetworkFee<'info> {
+    #[account(mut, seeds = [NETWORK_FEE_SEED, &gatekeeper_network.key.to_bytes()], bump, constraint = fee.version == FEE_VERSION @ ErrorCode::AccountNotMigrated)]
+    fee: Account<'info, Fee>,
+    gatekeeper_network: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateFeeVault<'info> {
+    #[account(
+  init,
+  payer = authority,
+  space = FeeVault::get_space(),
+  seeds = [FEE_VAULT_SEED.as_ref(), authority.key.to_bytes().as_ref(), gatekeeper_network.key.to_bytes().as_ref()],
+  bump
+  )]
+    fee_vault: Account<'info, FeeVault>,
+    #[account(mut)]
+    authority: Signer<'info>, // the gatekeeper
+    /// CHECK: This can be any public key (in reality it should match a known gatekeeper network)
+    gatekeeper_network: UncheckedAccount<'info>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimFees<'info> {
+    #[account(mut, seeds = [FEE_VAULT_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes()], bump = fee_vault.bump)]
+    fee_vault: Account<'info, FeeVault>,
+    #[account(mut)]
+    authority: Signer<'info>, // the gatekeeper
+    /// CHECK: This can be any public key (in reality it should match a known gatekeeper network)
+    gatekeeper_network: UncheckedAccount<'info>,
+    #[account(seeds = [FEE_SEED, &authority.key.to_bytes(), &gatekeeper_network.key.to_bytes()], bump)]
+    /// The fee account of the gatekeeper. It may be empty, if the fee has been removed.
+    /// CHECK: The derivation is checked here - the fee is parsed in the program if it exists.
+    fee: UncheckedAccount<'info>,
+    #[account(mut)]
+    /// The treasury of the fee, or its first split recipient, if set, otherwise the gatekeeper
+    /// CHECK: Checked against the fee in the program
+    destination: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct LinkWallet<'info> {
+    #[account(
+  init,
+  payer = linked_wallet,
+  space = WalletLink::get_space(),
+  seeds = [WALLET_LINK_SEED.as_ref(), source_wallet.key.to_bytes().as_ref(), linked_wallet.key.to_bytes().as_ref()],
+  bump
+  )]
+    wallet_link: Account<'info, WalletLink>,
+    /// The wallet holding the component passes
+    source_wallet: Signer<'info>,
+    #[account(mut)]
+    /// The wallet that will receive derived passes
+    linked_wallet: Signer<'info>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnlinkWallet<'info> {
+    #[account(
+  mut,
+  close = authority,
+  seeds = [WALLET_LINK_SEED, &wallet_link.source_wallet.to_bytes(), &wallet_link.linked_wallet.to_bytes()],
+  bump = wallet_link.bump,
+  constraint = authority.key() == wallet_link.source_wallet || authority.key() == wallet_link.linked_wallet @ ErrorCode::InvalidWalletLink
+  )]
+    wallet_link: Account<'info, WalletLink>,
+    #[account(mut)]
+    /// Either of the two linked wallets can remove the link
+    authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPassState<'info> {
+    /// CHECK: Loaded in the program, accepting older versions (see migrate_derived_pass)
+    #[account(owner = id())]
+    derived_pass: UncheckedAccount<'info>,
+    /// The authority of the derived pass
+    authority: Signer<'info>,
+    #[account(mut, owner = Gateway::id())]
+    /// The gateway token to change the state of
+    /// CHECK: Checked to be on the derived pass network in the program, and in the CPI to the Gateway program
+    gateway_token: UncheckedAccount<'info>,
+    #[account(seeds = [GATEKEEPER_SEED, &authority.key.to_bytes()], bump)]
+    /// A PDA representing the gatekeeper.
+    /// CHECK: Checked in the CPI to the Gateway program
+    derived_gatekeeper: UncheckedAccount<'info>,
+    #[account(owner = Gateway::id())]
+    /// The account linking the derived gatekeeper to the derived_pass gatekeeper network
+    /// CHECK: Checked in the CPI to the Gateway program
+    derived_gatekeeper_account: UncheckedAccount<'info>,
+    gateway_program: Program<'info, Gateway>,
+    rent: Sysvar<'info, Rent>,
+    system_program: Program<'info, System>,
+}
+impl<'info> SetPassState<'info> {
+    /// Load the derived pass and the gateway token, checking that the signer is the authority of the derived pass,
+    /// and that the gateway token is on its network
+    fn load_derived_pass_and_token(
+        &self,
+    ) -> Result<(DerivedPass, solana_gateway::state::GatewayToken)> {
+        let derived_pass = load_derived_pass(&self.derived_pass)?;
+        require!(
+            derived_pass.authority == self.authority.key(),
+            ErrorCode::InvalidDerivedPassAuthority
+        );
+
+        let gateway_token = solana_gateway::Gateway::parse_gateway_token(&self.gateway_token)
+            .map_err(|_| error!(ErrorCode::InvalidGatewayToken))?;
+        require!(
+            gateway_token.gatekeeper_network == self.derived_pass.key(),
+            ErrorCode::InvalidGatewayToken
+        );
+
+        Ok((derived_pass, gateway_token))
+    }
+
+    fn to_gateway_token_state_params<'b>(
+        &self,
+        authority_signer_seeds: &'b [&'b [u8]],
+    ) -> GatewayTokenStateParams<'info, 'b> {
+        GatewayTokenStateParams {
+            gateway_token: self.gateway_token.to_account_info(),
+            gatekeeper: self.derived_gatekeeper.to_account_info(),
+            gatekeeper_account: self.derived_gatekeeper_account.to_account_info(),
+            authority_signer_seeds,
+            rent: self.rent.clone(),
+            system_program: self.system_program.clone(),
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct CreateDerivedPassStats<'info> {
+    /// CHECK: Loaded in the program, accepting older versions (see migrate_derived_pass)
+    #[account(owner = id())]
+    derived_pass: UncheckedAccount<'info>,
+    #[account(
+  init,
+  payer = payer,
+  space = DerivedPassStats::get_space(),
+  seeds = [STATS_SEED.as_ref(), derived_pass.key().to_bytes().as_ref()],
+  bump
+  )]
+    stats: Account<'info, DerivedPassStats>,
+    #[account(mut)]
+    payer: Signer<'info>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateDerivedPass<'info> {
+    /// CHECK: Loaded in the program, accepting older versions
+    #[account(mut, owner = id())]
+    derived_pass: UncheckedAccount<'info>,
+    #[account(mut)]
+    payer: Signer<'info>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateFee<'info> {
+    /// CHECK: Loaded in the program, accepting older versions
+    #[account(mut, owner = id())]
+    fee: UncheckedAccount<'info>,
+    #[account(mut)]
+    payer: Signer<'info>,
+    system_program: Program<'info, System>,
+}
+
 #[error_code]
 pub enum ErrorCode {
     #[msg("At least one component pass is missing")]
@@ -430,4 +1934,133 @@ pub enum ErrorCode {
 
     #[msg("Invalid gateway token")]
     InvalidGatewayToken,
+
+    #[msg("The wallet link is invalid or does not link to the recipient")]
+    InvalidWalletLink,
+
+    #[msg("This derived pass does not accept component passes from linked wallets")]
+    LinkedWalletsDisabled,
+
+    #[msg("A derived pass cannot include itself as a constituent pass")]
+    CircularDerivedPass,
+
+    #[msg("The derived pass accounts passed to issue_many are missing or invalid")]
+    InvalidDerivedPassAccounts,
+
+    #[msg("The remaining accounts do not match the component account counts")]
+    IncorrectAccountLayout,
+
+    #[msg("The fee discount is invalid")]
+    InvalidFeeDiscount,
+
+    #[msg("The fee voucher is invalid or does not apply to this transaction")]
+    InvalidVoucher,
+
+    #[msg("The fee voucher signature was not verified in this transaction")]
+    InvalidVoucherSignature,
+
+    #[msg("The fee voucher has already been used")]
+    VoucherAlreadyUsed,
+
+    #[msg("The fee voucher has expired")]
+    VoucherExpired,
+
+    #[msg("The fee vault is missing or does not match the gatekeeper and gatekeeper network")]
+    InvalidFeeVault,
+
+    #[msg("The account passed to receive a fee does not match the fee's treasury")]
+    TreasuryMismatch,
+
+    #[msg("The fee splits must be for at most four distinct recipients, sum to 100% and not be combined with a treasury")]
+    InvalidFeeSplit,
+
+    #[msg("The recipient of a fee split was not passed")]
+    FeeSplitMismatch,
+
+    #[msg("The component pass fees exceed the fee caps of the derived pass")]
+    FeeCapExceeded,
+
+    #[msg("The default fee account of a component pass's gatekeeper network was not passed")]
+    MissingNetworkFee,
+
+    #[msg("The effective time of a fee change must be in the future")]
+    InvalidEffectiveTime,
+
+    #[msg("The fee has no pending change")]
+    NoPendingFee,
+
+    #[msg("The pending fee change has not yet taken effect")]
+    PendingFeeNotEffective,
+
+    #[msg("The pending fee change has already taken effect")]
+    PendingFeeAlreadyEffective,
+
+    #[msg("The account is not a supported version of the expected account type")]
+    UnsupportedAccountVersion,
+
+    #[msg("The account is already the current version")]
+    AccountAlreadyMigrated,
+
+    #[msg("The derived pass has reached its maximum supply")]
+    MaxSupplyReached,
+
+    #[msg("The derived pass has been issued the maximum number of times in the current window")]
+    IssueQuotaExceeded,
+
+    #[msg("The statistics account does not belong to the derived pass")]
+    InvalidDerivedPassStats,
+
+    #[msg("The recipient state account is missing or does not belong to the derived pass and recipient")]
+    InvalidRecipientState,
+
+    #[msg("The derived pass cannot be refreshed again yet")]
+    RefreshTooSoon,
+
+    #[msg("The derived pass has been refreshed the maximum number of times")]
+    MaxRefreshesReached,
+
+    #[msg("A component pass does not have an expiry time")]
+    ComponentPassNotExpirable,
+
+    #[msg("A component pass expires before the minimum component validity of the derived pass")]
+    ComponentPassExpiresTooSoon,
+
+    #[msg("The issue window must end after it starts")]
+    InvalidIssueWindow,
+
+    #[msg("The issue window of the derived pass has not opened yet")]
+    IssueWindowNotOpen,
+
+    #[msg("The issue window of the derived pass has closed")]
+    IssueWindowClosed,
+
+    #[msg("The fixed expiry time of the derived pass has passed")]
+    DerivedPassExpired,
+
+    #[msg("The derived pass can only be refreshed close to its expiry")]
+    OutsideRefreshWindow,
+
+    #[msg("The signer is not the authority of the derived pass")]
+    InvalidDerivedPassAuthority,
+
+    #[msg("An error occurred changing the state of the pass")]
+    SetStateError,
+
+    #[msg("The pass has been revoked, and cannot be issued to the same wallet again")]
+    PassRevoked,
+
+    #[msg("The window duration of the issue quota must be positive")]
+    InvalidIssueQuota,
+
+    #[msg("The maximum horizon of a refresh extended from expiry must be positive")]
+    InvalidRefreshExtension,
+
+    #[msg("A network default fee is paid to the issuing gatekeeper, so cannot have a treasury or fee splits")]
+    InvalidNetworkFeeRouting,
+
+    #[msg("The account must be migrated to the current version first")]
+    AccountNotMigrated,
+
+    #[msg("A fee override cannot escrow fees, as claims from the fee vault follow the gatekeeper's fee")]
+    InvalidFeeOverrideRouting,
 }
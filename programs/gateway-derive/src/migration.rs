@@ -0,0 +1,184 @@
+use crate::{
+    id, util::DISCRIMINATOR_SIZE, AccountInfo, DerivedPass, DerivedPassProperties, ErrorCode, Fee,
+    FeeRouting, FeeType, IssuanceCounters, Pubkey, RefreshExtension,
+};
+use anchor_lang::{
+    error,
+    error::Error,
+    prelude::{borsh, msg, Program, Rent, Signer, System},
+    require,
+    solana_program::{program::invoke, system_instruction, sysvar::Sysvar},
+    AccountDeserialize, AccountSerialize, AnchorDeserialize, Discriminator, ToAccountInfo,
+};
+
+/// The current layout versions of the program's accounts.
+/// Accounts of an older version are accepted in issue, issue_many and refresh until they are upgraded
+/// with migrate_derived_pass or migrate_fee. Other instructions require the current version.
+pub const DERIVED_PASS_VERSION: u8 = 1;
+pub const FEE_VERSION: u8 = 1;
+
+/// Space reserved at the end of DerivedPass and Fee accounts, so that fields can be added without a migration.
+/// Fields added in the reserved space must read as their default from zeroed bytes (e.g. Option or bool).
+/// The properties are the last field of a derived pass, so that new properties can be appended.
+pub const RESERVED_SPACE: usize = 64;
+
+/// The properties of a version 0 derived pass
+#[derive(AnchorDeserialize)]
+struct DerivedPassPropertiesV0 {
+    expire_duration: Option<i64>,
+    expire_on_use: bool,
+    refresh_disabled: bool,
+}
+
+/// The layout of a version 0 derived pass
+#[derive(AnchorDeserialize)]
+struct DerivedPassV0 {
+    version: u8,
+    authority: Pubkey,
+    gatekeeper_bump: u8,
+    source_gkns: Vec<Pubkey>,
+    properties: DerivedPassPropertiesV0,
+}
+
+impl From<DerivedPassV0> for DerivedPass {
+    fn from(derived_pass: DerivedPassV0) -> Self {
+        DerivedPass {
+            version: derived_pass.version,
+            authority: derived_pass.authority,
+            gatekeeper_bump: derived_pass.gatekeeper_bump,
+            source_gkns: derived_pass.source_gkns,
+            properties: DerivedPassProperties {
+                expire_duration: derived_pass.properties.expire_duration,
+                expire_on_use: derived_pass.properties.expire_on_use,
+                refresh_disabled: derived_pass.properties.refresh_disabled,
+                allow_linked_wallets: false,
+                fee_caps: None,
+                max_supply: None,
+                max_issues_per_window: None,
+                min_refresh_interval: None,
+                max_refreshes: None,
+                require_component_expiry: false,
+                min_component_validity: None,
+                issue_not_before: None,
+                issue_not_after: None,
+                issue_window_applies_to_refresh: false,
+                expire_at: None,
+                refresh_window: None,
+                refresh_extension: RefreshExtension::FromNow,
+            },
+            counters: IssuanceCounters::default(),
+        }
+    }
+}
+
+/// The layout of a version 0 fee
+#[derive(AnchorDeserialize)]
+struct FeeV0 {
+    version: u8,
+    fee_type: FeeType,
+    percentage: u8,
+    issue_amount: u64,
+    refresh_amount: u64,
+    mint: Option<Pubkey>,
+}
+
+impl From<FeeV0> for Fee {
+    fn from(fee: FeeV0) -> Self {
+        Fee {
+            version: fee.version,
+            fee_type: fee.fee_type,
+            percentage: fee.percentage,
+            issue_amount: fee.issue_amount,
+            refresh_amount: fee.refresh_amount,
+            mint: fee.mint,
+            discount: None,
+            routing: FeeRouting::default(),
+            pending: None,
+            batch_discount: false,
+        }
+    }
+}
+
+/// Check the owner and discriminator of a program account, and return its version
+fn get_account_version<T: Discriminator>(account_info: &AccountInfo) -> Result<u8, Error> {
+    require!(
+        *account_info.owner == id(),
+        ErrorCode::UnsupportedAccountVersion
+    );
+    let data = account_info.try_borrow_data()?;
+    require!(
+        data.len() > DISCRIMINATOR_SIZE && data[..DISCRIMINATOR_SIZE] == T::discriminator(),
+        ErrorCode::UnsupportedAccountVersion
+    );
+    Ok(data[DISCRIMINATOR_SIZE])
+}
+
+/// Load a derived pass of any supported version, converting it to the current layout.
+/// The version field is left unchanged, so that the original version can still be told.
+pub fn load_derived_pass(account_info: &AccountInfo) -> Result<DerivedPass, Error> {
+    let version = get_account_version::<DerivedPass>(account_info)?;
+    let data = account_info.try_borrow_data()?;
+    match version {
+        0 => DerivedPassV0::deserialize(&mut &data[DISCRIMINATOR_SIZE..])
+            .map(DerivedPass::from)
+            .map_err(|_| error!(ErrorCode::UnsupportedAccountVersion)),
+        DERIVED_PASS_VERSION => DerivedPass::try_deserialize(&mut &data[..]),
+        _ => Err(error!(ErrorCode::UnsupportedAccountVersion)),
+    }
+}
+
+/// Load a fee of any supported version, converting it to the current layout.
+pub fn load_fee(account_info: &AccountInfo) -> Result<Fee, Error> {
+    let version = get_account_version::<Fee>(account_info)?;
+    let data = account_info.try_borrow_data()?;
+    match version {
+        0 => FeeV0::deserialize(&mut &data[DISCRIMINATOR_SIZE..])
+            .map(Fee::from)
+            .map_err(|_| error!(ErrorCode::UnsupportedAccountVersion)),
+        FEE_VERSION => Fee::try_deserialize(&mut &data[..]),
+        _ => Err(error!(ErrorCode::UnsupportedAccountVersion)),
+    }
+}
+
+/// Write a derived pass loaded with load_derived_pass back to its account.
+/// A version 0 derived pass has no space for the issuance counters, so is left unchanged until migrated.
+pub fn save_derived_pass(
+    account_info: &AccountInfo,
+    derived_pass: &DerivedPass,
+) -> Result<(), Error> {
+    let mut data = account_info.try_borrow_mut_data()?;
+    match derived_pass.version {
+        0 => Ok(()),
+        _ => derived_pass.try_serialize(&mut &mut data[..]),
+    }
+}
+
+/// Resize an account to the given space, topping up its rent from the payer, and write the account to it.
+pub fn write_migrated_account<'a, T: AccountSerialize>(
+    account_info: &AccountInfo<'a>,
+    account: &T,
+    space: usize,
+    payer: &Signer<'a>,
+    system_program: &Program<'a, System>,
+) -> Result<(), Error> {
+    let rent_exempt_minimum = Rent::get()?.minimum_balance(space);
+    let top_up = rent_exempt_minimum.saturating_sub(account_info.lamports());
+    if top_up > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account_info.key, top_up),
+            &[
+                payer.to_account_info(),
+                account_info.clone(),
+                system_program.to_account_info(),
+            ],
+        )?;
+    }
+
+    if account_info.data_len() < space {
+        account_info.realloc(space, true)?;
+    }
+
+    msg!("Migrated account {}", account_info.key);
+    let mut data = account_info.try_borrow_mut_data()?;
+    account.try_serialize(&mut &mut data[..])
+}
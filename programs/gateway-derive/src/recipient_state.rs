@@ -0,0 +1,111 @@
+use crate::{
+    id, util::create_pda_account, AccountInfo, DerivedPass, ErrorCode, Pubkey, RecipientState,
+};
+use anchor_lang::{
+    error::Error,
+    prelude::{Account, AccountsExit},
+    require,
+    solana_program::clock::UnixTimestamp,
+    AccountSerialize,
+};
+
+pub(crate) const RECIPIENT_STATE_SEED: &[u8; 29] = br"gateway_derive_recipient_seed";
+
+/// The address and bump of the state of a derived pass held by a recipient
+pub fn derive_recipient_state_address(derived_pass: &Pubkey, recipient: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            RECIPIENT_STATE_SEED,
+            &derived_pass.to_bytes(),
+            &recipient.to_bytes(),
+        ],
+        &id(),
+    )
+}
+
+/// Record the issue of a derived pass to a recipient, creating the recipient state if needed,
+/// and resetting its refresh count for the new gateway token.
+/// The recipient state is only kept for derived passes with refresh limits.
+pub fn record_recipient_issue<'a>(
+    derived_pass_address: &Pubkey,
+    derived_pass: &DerivedPass,
+    recipient: &AccountInfo<'a>,
+    recipient_state: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    now: UnixTimestamp,
+) -> Result<(), Error> {
+    if !derived_pass.properties.has_refresh_limits() {
+        return Ok(());
+    }
+
+    let (address, bump) = derive_recipient_state_address(derived_pass_address, recipient.key);
+    require!(
+        *recipient_state.key == address,
+        ErrorCode::InvalidRecipientState
+    );
+    if *recipient_state.owner != id() {
+        create_pda_account(
+            recipient,
+            recipient_state,
+            RecipientState::get_space(),
+            &[
+                RECIPIENT_STATE_SEED,
+                &derived_pass_address.to_bytes(),
+                &recipient.key.to_bytes(),
+                &[bump],
+            ],
+            system_program,
+        )?;
+    }
+
+    let state = RecipientState {
+        version: 0,
+        bump,
+        derived_pass: *derived_pass_address,
+        recipient: *recipient.key,
+        last_issue: now,
+        last_refresh: None,
+        refresh_count: 0,
+    };
+    let mut data = recipient_state.try_borrow_mut_data()?;
+    state.try_serialize(&mut &mut data[..])
+}
+
+/// Check the refresh limits of the derived pass against the recipient state, and record the refresh.
+pub fn record_recipient_refresh(
+    derived_pass_address: &Pubkey,
+    derived_pass: &DerivedPass,
+    recipient: &Pubkey,
+    recipient_state: &AccountInfo,
+    now: UnixTimestamp,
+) -> Result<(), Error> {
+    if !derived_pass.properties.has_refresh_limits() {
+        return Ok(());
+    }
+
+    let (address, _) = derive_recipient_state_address(derived_pass_address, recipient);
+    require!(
+        *recipient_state.key == address && *recipient_state.owner == id(),
+        ErrorCode::InvalidRecipientState
+    );
+    let mut state: Account<RecipientState> = Account::try_from(recipient_state)?;
+
+    if let Some(min_refresh_interval) = derived_pass.properties.min_refresh_interval {
+        let last_activity = state.last_refresh.unwrap_or(state.last_issue);
+        require!(
+            now >= last_activity.saturating_add(min_refresh_interval),
+            ErrorCode::RefreshTooSoon
+        );
+    }
+    if let Some(max_refreshes) = derived_pass.properties.max_refreshes {
+        require!(
+            state.refresh_count < max_refreshes,
+            ErrorCode::MaxRefreshesReached
+        );
+    }
+
+    state.last_refresh = Some(now);
+    state.refresh_count = state.refresh_count.saturating_add(1);
+
+    state.exit(&id())
+}
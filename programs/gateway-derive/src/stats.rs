@@ -0,0 +1,54 @@
+use crate::{id, AccountInfo, Action, DerivedPassStats, ErrorCode, FeePayment, Pubkey};
+use anchor_lang::{
+    error,
+    error::Error,
+    prelude::{Account, AccountsExit},
+    solana_program::clock::UnixTimestamp,
+};
+
+pub(crate) const STATS_SEED: &[u8; 25] = br"gateway_derive_stats_seed";
+
+/// The address of the statistics account of a derived pass, given its bump
+pub fn get_stats_address(derived_pass: &Pubkey, bump: u8) -> Result<Pubkey, Error> {
+    Pubkey::create_program_address(&[STATS_SEED, &derived_pass.to_bytes(), &[bump]], &id())
+        .map_err(|_| error!(ErrorCode::InvalidDerivedPassStats))
+}
+
+/// The total amount of the fees in a fee breakdown
+pub fn total_fees(fees: &[FeePayment]) -> Result<u64, Error> {
+    fees.iter().try_fold(0u64, |total, fee| {
+        total
+            .checked_add(fee.amount)
+            .ok_or_else(|| error!(ErrorCode::PaymentOverflow))
+    })
+}
+
+/// Record an issue or refresh of a derived pass, and the fees paid for it, in its statistics account.
+/// Derived passes created before statistics were added have no statistics account until
+/// create_derived_pass_stats is called, in which case the activity is not recorded.
+/// The address of the statistics account must be checked by the caller.
+pub fn record_activity(
+    stats_account: &AccountInfo,
+    action: Action,
+    fees_paid: u64,
+    now: UnixTimestamp,
+) -> Result<(), Error> {
+    if *stats_account.owner != id() {
+        return Ok(());
+    }
+
+    let mut stats: Account<DerivedPassStats> = Account::try_from(stats_account)?;
+    match action {
+        Action::Issue => {
+            stats.issued = stats.issued.saturating_add(1);
+            stats.issue_fees_paid = stats.issue_fees_paid.saturating_add(fees_paid);
+        }
+        Action::Refresh => {
+            stats.refreshed = stats.refreshed.saturating_add(1);
+            stats.refresh_fees_paid = stats.refresh_fees_paid.saturating_add(fees_paid);
+        }
+    }
+    stats.last_activity = now;
+
+    stats.exit(&id())
+}
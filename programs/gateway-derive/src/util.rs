@@ -1,30 +1,76 @@
-use crate::{id, AccountInfo, ErrorCode, Fee, Pubkey};
+use crate::{
+    id,
+    migration::{load_fee, FEE_VERSION},
+    voucher::apply_voucher,
+    AccountInfo, Action, DerivedPassProperties, ErrorCode, Fee, FeeCaps, FeeDiscount, FeePayment,
+    FeeRouting, FeeSplit, FeeVault, FeeVoucher, Pubkey, RefreshExtension, WalletLink,
+};
 use anchor_lang::solana_program::clock::UnixTimestamp;
 use anchor_lang::{
     error,
     error::Error,
     prelude::msg,
-    prelude::{Account, Program, Signer, System},
+    prelude::{Account, Id, Program, Signer, System},
     require,
     solana_program::{
-        clock::Clock, program::invoke, system_instruction, system_program, sysvar::Sysvar,
+        clock::Clock,
+        program::{invoke, invoke_signed},
+        system_instruction, system_program,
+        sysvar::{rent::Rent, Sysvar},
     },
-    Key, ToAccountInfo,
+    ToAccountInfo,
 };
 use num_traits::cast::AsPrimitive;
-use solana_gateway::{state::GatewayToken, Gateway};
-use std::collections::HashMap;
+use solana_gateway::{
+    state::{GatewayToken, GatewayTokenState},
+    Gateway,
+};
 
 pub const DISCRIMINATOR_SIZE: usize = 8;
 pub const PUBKEY_SIZE: usize = 32;
 pub const U8_SIZE: usize = 1;
+pub const U16_SIZE: usize = 2;
+pub const U32_SIZE: usize = 4;
 pub const U64_SIZE: usize = 8;
 
 pub(crate) const FEE_SEED: &[u8; 23] = br"gateway_derive_fee_seed";
 pub(crate) const GATEKEEPER_SEED: &[u8; 22] = br"gateway_derive_gk_seed";
+pub(crate) const WALLET_LINK_SEED: &[u8; 24] = br"gateway_derive_link_seed";
+pub(crate) const FEE_VAULT_SEED: &[u8; 25] = br"gateway_derive_vault_seed";
+pub(crate) const FEE_OVERRIDE_SEED: &[u8; 32] = br"gateway_derive_fee_override_seed";
+pub(crate) const NETWORK_FEE_SEED: &[u8; 31] = br"gateway_derive_network_fee_seed";
+
+/// The number of remaining accounts passed to issue_many for each derived pass:
+/// [derived_pass, gateway_token, derived_gatekeeper, derived_gatekeeper_account, derived_pass_stats, recipient_state]
+pub const ISSUE_MANY_ACCOUNTS_PER_DERIVED_PASS: usize = 6;
+
+/// The minimum number of accounts in each component pass group in the grouped account layout:
+/// [gateway_token, fee, gatekeeper]
+pub const MIN_ACCOUNTS_PER_COMPONENT: usize = 3;
+
+/// The maximum number of recipients a fee can be split between (see FeeSplit)
+pub const MAX_FEE_SPLITS: usize = 4;
+/// Fee splits are expressed in basis points, which must sum to 100%
+pub const BASIS_POINTS_TOTAL: u16 = 10_000;
+
+/// The accounts to be paid the fee for a component pass, with the share of the fee (in basis points) of each
+type FeePayees<'a, 'b> = Vec<(&'b AccountInfo<'a>, u16)>;
+
+type ParsedGatewayTokenAccountWithFee<'a, 'b> = (GatewayToken, u64, Option<Fee>, FeePayees<'a, 'b>);
+
+/// Adjustments to the gatekeeper fees, applied in this order when calculating the fees to be paid
+#[derive(Default)]
+pub struct FeeAdjustments<'c> {
+    /// The gatekeeper networks of valid passes held by the recipient, for holder discounts (see FeeDiscount)
+    pub discount_networks: Vec<Pubkey>,
+    /// A redeemed fee voucher (see FeeVoucher)
+    pub voucher: Option<&'c FeeVoucher>,
+    /// The fee caps of the derived pass (see FeeCaps)
+    pub caps: Option<FeeCaps>,
+}
 
-type ParsedGatewayTokenAccountWithFee<'a, 'b> =
-    (GatewayToken, u64, Option<Fee>, &'b AccountInfo<'a>);
+/// The fee breakdown, and the payments to be made to each gatekeeper
+type FeeBreakdown<'a, 'b> = (Vec<FeePayment>, Vec<(&'b AccountInfo<'a>, u64)>);
 
 pub fn matches_gatekeeper_network(
     gateway_token: &GatewayToken,
@@ -33,37 +79,76 @@ pub fn matches_gatekeeper_network(
     gateway_token.gatekeeper_network == *gatekeeper_network
 }
 
+/// Check that a gateway token exists for the gatekeeper network, and that it is valid.
+/// The token may be owned by any of the expected owners (the recipient, or a wallet linked to it),
+/// and must satisfy the component expiry requirements of the derived pass properties.
 pub fn check_has_matching_gateway_token(
     gateway_tokens: &[ParsedGatewayTokenAccountWithFee],
     gatekeeper_network: &Pubkey,
-    expected_owner: &Pubkey,
+    expected_owners: &[Pubkey],
+    properties: &DerivedPassProperties,
 ) -> Result<(), Error> {
     let found_gateway_token = gateway_tokens.iter().find(|(gateway_token, _, _, _)| {
         matches_gatekeeper_network(gateway_token, gatekeeper_network)
     });
 
     match found_gateway_token {
-        Some((gateway_token, balance, _, _)) => Gateway::verify_gateway_token(
-            gateway_token,
-            expected_owner,
-            gatekeeper_network,
-            *balance,
-            None,
-        )
-        .map_err(|_| error!(ErrorCode::InvalidComponentPass)),
+        Some((gateway_token, balance, _, _)) => {
+            require!(
+                expected_owners.contains(&gateway_token.owner_wallet),
+                ErrorCode::InvalidComponentPass
+            );
+            Gateway::verify_gateway_token(
+                gateway_token,
+                &gateway_token.owner_wallet,
+                gatekeeper_network,
+                *balance,
+                None,
+            )
+            .map_err(|_| error!(ErrorCode::InvalidComponentPass))?;
+            check_component_expiry(gateway_token, properties)
+        }
         _ => Err(error!(ErrorCode::MissingComponentPass)),
     }
 }
 
+/// Check that a component pass has an expiry time, if required, and that it remains valid for at least
+/// the minimum component validity of the derived pass.
+/// The gateway program only checks that a component pass is valid now, so without this a component pass
+/// about to expire yields a derived pass valid for its full expire_duration.
+fn check_component_expiry(
+    gateway_token: &GatewayToken,
+    properties: &DerivedPassProperties,
+) -> Result<(), Error> {
+    if properties.require_component_expiry {
+        require!(
+            gateway_token.expire_time.is_some(),
+            ErrorCode::ComponentPassNotExpirable
+        );
+    }
+
+    if let (Some(min_validity), Some(expire_time)) =
+        (properties.min_component_validity, gateway_token.expire_time)
+    {
+        let now = Clock::get()?.unix_timestamp;
+        require!(
+            expire_time >= now.saturating_add(min_validity),
+            ErrorCode::ComponentPassExpiresTooSoon
+        );
+    }
+
+    Ok(())
+}
+
 /// Parse the account token into a fee account.
 /// If the fee account is missing, return None
 /// If the fee account is not missing, but not owned by the GatewayDerive Program, return an error
-fn parse_fee_account<'a, 'b>(
-    account_info: &'b AccountInfo<'a>,
+fn parse_fee_account(
+    account_info: &AccountInfo,
     gatekeeper: &Pubkey,
     gatekeeper_network: &Pubkey,
     fee_bump: u8,
-) -> Result<Option<Account<'a, Fee>>, Error> {
+) -> Result<Option<Fee>, Error> {
     let expected_fee_account = derive_fee_address(gatekeeper, gatekeeper_network, fee_bump)
         .map_err(|_| error!(ErrorCode::InvalidFeeAccount))?;
 
@@ -71,6 +156,12 @@ fn parse_fee_account<'a, 'b>(
         return Err(error!(ErrorCode::InvalidFeeAccount));
     }
 
+    load_optional_fee(account_info)
+}
+
+/// Load a fee account at an address already known to be correct, accepting older versions (see migrate_fee).
+/// An empty account means that no fee is set.
+fn load_optional_fee(account_info: &AccountInfo) -> Result<Option<Fee>, Error> {
     if account_info.owner == &system_program::id() {
         if account_info.try_lamports().unwrap() == 0 {
             return Ok(None);
@@ -78,57 +169,392 @@ fn parse_fee_account<'a, 'b>(
             return Err(error!(ErrorCode::InvalidFeeAccount));
         }
     } else if account_info.owner == &id() {
-        return Ok(Some(Account::try_from(account_info)?));
+        return Ok(Some(load_fee(account_info)?));
     }
 
     Err(error!(ErrorCode::InvalidFeeAccount))
 }
 
-/// Given an array of remaining accounts of the form
-/// [gt1, gt2, ... gtN, fee1, fee2, ... feeN, gatekeeper1, gatekeeper2, ... gatekeeperN],
-/// return a vector of entries combining the gateway token with the associated fee and gatekeeper
-fn parse_accounts<'a, 'b>(
+/// The accounts passed for a single component pass
+pub struct ComponentAccounts<'a, 'b> {
+    /// The component gateway token
+    pub gateway_token: &'b AccountInfo<'a>,
+    /// The fee account of the gatekeeper that issued the component pass (it may be empty)
+    pub fee: &'b AccountInfo<'a>,
+    /// The gatekeeper that issued the component pass, to receive payment,
+    /// or its fee vault or treasury, depending on the routing of its fee (see FeeRouting)
+    pub gatekeeper: &'b AccountInfo<'a>,
+    /// Any further accounts in the group, e.g. the recipients of a split fee.
+    pub extra: &'b [AccountInfo<'a>],
+}
+
+/// Split the remaining accounts into the accounts for each component pass, and the shared accounts
+/// (e.g. a wallet link) that follow them.
+///
+/// The accounts are grouped by component pass, of the form
+/// [gt1, fee1, gatekeeper1, ...extra1, gt2, fee2, gatekeeper2, ...extra2, ...],
+/// where the number of accounts in each group is passed explicitly in component_account_counts.
+/// The extra accounts in each group are for further per-component accounts (e.g. the recipients of a split fee),
+/// so that they can be added without shifting the accounts of the other components.
+pub fn split_component_accounts<'a, 'b>(
     accounts: &'b [AccountInfo<'a>],
-    fee_bumps: &[u8],
-) -> Result<Vec<ParsedGatewayTokenAccountWithFee<'a, 'b>>, Error> {
-    let gateway_token_count = accounts.len() / 3;
+    component_account_counts: &[u8],
+    component_count: usize,
+) -> Result<(Vec<ComponentAccounts<'a, 'b>>, &'b [AccountInfo<'a>]), Error> {
+    if component_count != component_account_counts.len() {
+        return Err(error!(ErrorCode::IncorrectFeeBumpCount));
+    }
+
+    let mut remaining = accounts;
+    let components = component_account_counts
+        .iter()
+        .map(|count| {
+            let count = *count as usize;
+            require!(
+                count >= MIN_ACCOUNTS_PER_COMPONENT && count <= remaining.len(),
+                ErrorCode::IncorrectAccountLayout
+            );
+            let (group, rest) = remaining.split_at(count);
+            remaining = rest;
+
+            Ok(ComponentAccounts {
+                gateway_token: &group[0],
+                fee: &group[1],
+                gatekeeper: &group[2],
+                extra: &group[MIN_ACCOUNTS_PER_COMPONENT..],
+            })
+        })
+        .collect::<Result<Vec<ComponentAccounts<'a, 'b>>, Error>>()?;
+
+    Ok((components, remaining))
+}
+
+/// Return the accounts to be paid the fee for a component pass, according to the routing of the fee (see FeeRouting),
+/// checking that they match the passed-in accounts.
+fn get_fee_payees<'a, 'b>(
+    component: &ComponentAccounts<'a, 'b>,
+    fee: Option<&Fee>,
+    gateway_token: &GatewayToken,
+) -> Result<FeePayees<'a, 'b>, Error> {
+    let payee = component.gatekeeper;
+
+    match fee.map(|f| &f.routing) {
+        Some(routing) if routing.escrow => {
+            validate_fee_vault(
+                payee,
+                &gateway_token.issuing_gatekeeper,
+                &gateway_token.gatekeeper_network,
+            )?;
+            Ok(vec![(payee, BASIS_POINTS_TOTAL)])
+        }
+        Some(FeeRouting {
+            treasury: Some(treasury),
+            ..
+        }) => {
+            require!(payee.key == treasury, ErrorCode::TreasuryMismatch);
+            Ok(vec![(payee, BASIS_POINTS_TOTAL)])
+        }
+        Some(FeeRouting { splits, .. }) if !splits.is_empty() => {
+            require!(
+                *payee.key == gateway_token.issuing_gatekeeper,
+                ErrorCode::GatekeeperMismatch
+            );
+            // the split recipients are passed in the extra accounts of the component pass group
+            splits
+                .iter()
+                .map(|split| {
+                    let recipient = component
+                        .extra
+                        .iter()
+                        .find(|account_info| *account_info.key == split.recipient)
+                        .ok_or_else(|| error!(ErrorCode::FeeSplitMismatch))?;
+                    Ok((recipient, split.basis_points))
+                })
+                .collect()
+        }
+        _ => {
+            require!(
+                *payee.key == gateway_token.issuing_gatekeeper,
+                ErrorCode::GatekeeperMismatch
+            );
+            Ok(vec![(payee, BASIS_POINTS_TOTAL)])
+        }
+    }
+}
+
+/// Find a fee override for the derived pass among the extra accounts of a component pass, if one was passed.
+/// Only accounts owned by this program are considered, so that the override address is only derived if needed.
+fn find_fee_override(
+    extra_accounts: &[AccountInfo],
+    gatekeeper: &Pubkey,
+    gatekeeper_network: &Pubkey,
+    derived_pass: &Pubkey,
+) -> Result<Option<Fee>, Error> {
+    let mut candidates = extra_accounts
+        .iter()
+        .filter(|account_info| *account_info.owner == id())
+        .peekable();
+    if candidates.peek().is_none() {
+        return Ok(None);
+    }
 
-    msg!(
-        "Parsing all {} accounts ({} gateway tokens expected)",
-        accounts.len(),
-        gateway_token_count
+    let (override_address, _) = Pubkey::find_program_address(
+        &[
+            FEE_OVERRIDE_SEED,
+            &gatekeeper.to_bytes(),
+            &gatekeeper_network.to_bytes(),
+            &derived_pass.to_bytes(),
+        ],
+        &id(),
     );
+    candidates
+        .find(|account_info| *account_info.key == override_address)
+        .map(load_fee)
+        .transpose()
+}
+
+/// Find the default fee of the gatekeeper network (see create_network_fee) in the shared accounts.
+/// The network fee account must be passed (even if empty) for any component pass whose gatekeeper has no fee,
+/// so that the default fee cannot be avoided by omitting it.
+fn find_network_fee(
+    shared_accounts: &[AccountInfo],
+    gatekeeper_network: &Pubkey,
+) -> Result<Option<Fee>, Error> {
+    let (network_fee_address, _) =
+        Pubkey::find_program_address(&[NETWORK_FEE_SEED, &gatekeeper_network.to_bytes()], &id());
+    let network_fee = shared_accounts
+        .iter()
+        .find(|account_info| *account_info.key == network_fee_address)
+        .ok_or_else(|| error!(ErrorCode::MissingNetworkFee))?;
 
-    accounts[..gateway_token_count]
+    load_optional_fee(network_fee)
+}
+
+/// Given the accounts for each component pass,
+/// return a vector of entries combining the gateway token with the associated fee and the accounts to pay it to.
+/// If a derived pass is given, a fee override for it (see create_fee_override) takes precedence over the fee,
+/// if it is passed in the extra accounts of the component pass.
+pub fn parse_accounts<'a, 'b>(
+    components: &[ComponentAccounts<'a, 'b>],
+    shared_accounts: &[AccountInfo<'a>],
+    fee_bumps: &[u8],
+    derived_pass: Option<&Pubkey>,
+) -> Result<Vec<ParsedGatewayTokenAccountWithFee<'a, 'b>>, Error> {
+    msg!("Parsing {} gateway tokens", components.len());
+    let now = Clock::get()?.unix_timestamp;
+
+    components
         .iter()
         .enumerate()
-        .map(|(i, account)| {
-            let gateway_token = Gateway::parse_gateway_token(account)
+        .map(|(i, component)| {
+            let gateway_token = Gateway::parse_gateway_token(component.gateway_token)
                 .map_err(|_| error!(ErrorCode::InvalidComponentPass))?;
-            let balance: u64 = account.lamports.borrow().as_();
-            let fee: Option<Account<Fee>> = parse_fee_account(
-                &accounts[gateway_token_count + i],
+            let balance: u64 = component.gateway_token.lamports.borrow().as_();
+            let fee: Option<Fee> = parse_fee_account(
+                component.fee,
                 &gateway_token.issuing_gatekeeper,
                 &gateway_token.gatekeeper_network,
                 fee_bumps[i],
             )?;
-            let gatekeeper = &accounts[2 * gateway_token_count + i];
+            let fee_override = match derived_pass {
+                Some(derived_pass) => find_fee_override(
+                    component.extra,
+                    &gateway_token.issuing_gatekeeper,
+                    &gateway_token.gatekeeper_network,
+                    derived_pass,
+                )?,
+                None => None,
+            };
+            if fee_override.is_some() {
+                msg!(
+                    "Using the fee override of gatekeeper {}",
+                    gateway_token.issuing_gatekeeper
+                );
+            }
+
+            // the default fee of the gatekeeper network applies if the gatekeeper has not set a fee
+            let fee = match fee_override.or(fee) {
+                Some(fee) => Some(fee),
+                None => find_network_fee(shared_accounts, &gateway_token.gatekeeper_network)?,
+            };
+            // a scheduled fee change is charged once it takes effect, even if not yet applied to the fee account
+            let fee = fee.map(|mut fee| {
+                fee.apply_pending(now);
+                fee
+            });
+            let payees = get_fee_payees(component, fee.as_ref(), &gateway_token)?;
+
+            Ok((gateway_token, balance, fee, payees))
+        })
+        .collect::<Result<Vec<ParsedGatewayTokenAccountWithFee<'a, 'b>>, Error>>()
+}
 
-            require!(
-                *gatekeeper.key == gateway_token.issuing_gatekeeper,
-                ErrorCode::GatekeeperMismatch
-            );
+/// Check that the account is the fee vault of the gatekeeper on the gatekeeper network
+fn validate_fee_vault(
+    account_info: &AccountInfo,
+    gatekeeper: &Pubkey,
+    gatekeeper_network: &Pubkey,
+) -> Result<(), Error> {
+    require!(*account_info.owner == id(), ErrorCode::InvalidFeeVault);
+    let fee_vault: Account<FeeVault> =
+        Account::try_from(account_info).map_err(|_| error!(ErrorCode::InvalidFeeVault))?;
+    require!(
+        fee_vault.gatekeeper == *gatekeeper && fee_vault.gatekeeper_network == *gatekeeper_network,
+        ErrorCode::InvalidFeeVault
+    );
+    Ok(())
+}
 
-            Ok((
-                gateway_token,
-                balance,
-                fee.map(|f| f.into_inner()),
-                gatekeeper,
-            ))
+/// Transfer the fees accrued in the fee vault to their destinations (see get_fee_destinations),
+/// leaving enough lamports in the vault for it to remain rent-exempt.
+/// The destination accounts must include each destination; the first must be the first destination.
+/// Returns the amount claimed
+pub fn claim_from_fee_vault(
+    fee_vault: &AccountInfo,
+    destinations: &[(Pubkey, u16)],
+    destination_accounts: &[AccountInfo],
+) -> Result<u64, Error> {
+    require!(
+        destination_accounts
+            .first()
+            .map(|account_info| account_info.key)
+            == destinations.first().map(|(destination, _)| destination),
+        ErrorCode::TreasuryMismatch
+    );
+
+    let rent_exempt_minimum = Rent::get()?.minimum_balance(fee_vault.data_len());
+    let amount = fee_vault.lamports().saturating_sub(rent_exempt_minimum);
+    msg!("Claiming {} lamports from the fee vault", amount);
+
+    split_amount(amount, destinations)?
+        .into_iter()
+        .try_for_each::<_, Result<(), Error>>(|(destination, share)| {
+            let destination = destination_accounts
+                .iter()
+                .find(|account_info| *account_info.key == destination)
+                .ok_or_else(|| error!(ErrorCode::FeeSplitMismatch))?;
+
+            **fee_vault.try_borrow_mut_lamports()? -= share;
+            **destination.try_borrow_mut_lamports()? = destination
+                .lamports()
+                .checked_add(share)
+                .ok_or_else(|| error!(ErrorCode::PaymentOverflow))?;
+            Ok(())
+        })?;
+
+    Ok(amount)
+}
+
+/// Split an amount between recipients according to their shares in basis points, using checked arithmetic.
+/// Any remainder left by rounding down is added to the first recipient's share,
+/// so that the shares always add up to the full amount.
+pub fn split_amount<T: Copy>(amount: u64, shares: &[(T, u16)]) -> Result<Vec<(T, u64)>, Error> {
+    let mut split = shares
+        .iter()
+        .map(|(recipient, basis_points)| {
+            let share = u128::from(amount)
+                .checked_mul(u128::from(*basis_points))
+                .ok_or_else(|| error!(ErrorCode::PaymentOverflow))?
+                / u128::from(BASIS_POINTS_TOTAL);
+            let share = u64::try_from(share).map_err(|_| error!(ErrorCode::PaymentOverflow))?;
+            Ok((*recipient, share))
         })
-        .collect::<Result<Vec<ParsedGatewayTokenAccountWithFee<'a, 'b>>, Error>>()
+        .collect::<Result<Vec<(T, u64)>, Error>>()?;
+
+    let total = split
+        .iter()
+        .try_fold(0u64, |total, (_, share)| total.checked_add(*share))
+        .ok_or_else(|| error!(ErrorCode::PaymentOverflow))?;
+    let remainder = amount
+        .checked_sub(total)
+        .ok_or_else(|| error!(ErrorCode::PaymentUnderflow))?;
+    if let Some((_, first_share)) = split.first_mut() {
+        *first_share = first_share
+            .checked_add(remainder)
+            .ok_or_else(|| error!(ErrorCode::PaymentOverflow))?;
+    }
+
+    Ok(split)
 }
 
+/// Return the addresses that fees claimed from a fee vault are paid to, with the share of each in basis points:
+/// the treasury of the fee, if set, or the split recipients, if any, otherwise the gatekeeper.
+/// The fee account may be empty, if the fee was removed after fees accrued in the vault.
+pub fn get_fee_destinations(
+    fee: &AccountInfo,
+    gatekeeper: &Pubkey,
+) -> Result<Vec<(Pubkey, u16)>, Error> {
+    if *fee.owner != id() {
+        return Ok(vec![(*gatekeeper, BASIS_POINTS_TOTAL)]);
+    }
+
+    let fee = load_fee(fee)?;
+    match &fee.routing {
+        FeeRouting {
+            treasury: Some(treasury),
+            ..
+        } => Ok(vec![(*treasury, BASIS_POINTS_TOTAL)]),
+        FeeRouting { splits, .. } if !splits.is_empty() => Ok(splits
+            .iter()
+            .map(|split| (split.recipient, split.basis_points))
+            .collect()),
+        _ => Ok(vec![(*gatekeeper, BASIS_POINTS_TOTAL)]),
+    }
+}
+
+/// Check that the fee splits, if any, are for at most MAX_FEE_SPLITS distinct recipients,
+/// and sum to 100%. A fee cannot have both a treasury and splits.
+fn validate_fee_splits(routing: &FeeRouting) -> Result<(), Error> {
+    if routing.splits.is_empty() {
+        return Ok(());
+    }
+
+    require!(
+        routing.treasury.is_none() && routing.splits.len() <= MAX_FEE_SPLITS,
+        ErrorCode::InvalidFeeSplit
+    );
+    let total_basis_points: u32 = routing
+        .splits
+        .iter()
+        .map(|split| u32::from(split.basis_points))
+        .sum();
+    require!(
+        total_basis_points == u32::from(BASIS_POINTS_TOTAL),
+        ErrorCode::InvalidFeeSplit
+    );
+    let has_duplicates = routing.splits.iter().enumerate().any(|(i, split)| {
+        routing.splits[..i]
+            .iter()
+            .any(|other: &FeeSplit| other.recipient == split.recipient)
+    });
+    require!(!has_duplicates, ErrorCode::InvalidFeeSplit);
+
+    Ok(())
+}
+
+/// Check that the routing of a network default fee pays the issuing gatekeeper (directly or via its fee vault).
+/// The default fee is the gatekeeper's revenue, charged on its behalf where it has not set a fee,
+/// so the network cannot redirect it to a treasury or split it.
+pub fn validate_network_fee_routing(routing: &FeeRouting) -> Result<(), Error> {
+    require!(
+        routing.treasury.is_none() && routing.splits.is_empty(),
+        ErrorCode::InvalidNetworkFeeRouting
+    );
+    Ok(())
+}
+
+/// Check that a fee override pays its fees directly, rather than escrowing them.
+/// The fee vault of a gatekeeper on a gatekeeper network is shared by all its fees,
+/// and claims from it follow the routing of the gatekeeper's fee (see get_fee_destinations),
+/// so escrowed override fees would not be paid according to the routing of the override.
+pub fn validate_fee_override_routing(routing: &FeeRouting) -> Result<(), Error> {
+    require!(!routing.escrow, ErrorCode::InvalidFeeOverrideRouting);
+    Ok(())
+}
+
+/// Create or update a fee. The new amounts take effect immediately, replacing any change still pending
+/// (see schedule_fee_update).
+#[allow(clippy::too_many_arguments)]
 pub fn create_or_update_fee(
     fee: &mut Account<Fee>,
     issue_amount: u64,
@@ -136,60 +562,267 @@ pub fn create_or_update_fee(
     percentage: u8,
     fee_type: u8, // Type: FeeType- Anchor does not yet provide mappings for enums
     mint: Option<Pubkey>,
-) {
-    fee.version = 0;
+    discount: Option<FeeDiscount>,
+    routing: FeeRouting,
+    batch_discount: bool,
+) -> Result<(), Error> {
+    if let Some(discount) = discount {
+        require!(discount.percentage <= 100, ErrorCode::InvalidFeeDiscount);
+    }
+    validate_fee_splits(&routing)?;
+
+    fee.version = FEE_VERSION;
     fee.issue_amount = issue_amount;
     fee.refresh_amount = refresh_amount;
     fee.percentage = percentage;
     fee.fee_type = num::FromPrimitive::from_u8(fee_type).unwrap();
     fee.mint = mint;
+    fee.discount = discount;
+    fee.routing = routing;
+    fee.batch_discount = batch_discount;
+    fee.pending = None;
+    Ok(())
+}
+
+/// Apply a fee discount to an amount, if the recipient holds a pass on the discount network.
+/// The percentage discount is applied first, followed by the fixed amount. The result is never negative.
+pub fn apply_discount(
+    amount: u64,
+    discount: &Option<FeeDiscount>,
+    discount_networks: &[Pubkey],
+) -> Result<u64, Error> {
+    match discount {
+        Some(discount) if discount_networks.contains(&discount.gatekeeper_network) => {
+            let remaining_percentage = 100 - u64::from(discount.percentage);
+            let discounted = amount
+                .checked_mul(remaining_percentage)
+                .ok_or_else(|| error!(ErrorCode::PaymentOverflow))?
+                / 100;
+            Ok(discounted.saturating_sub(discount.amount))
+        }
+        _ => Ok(amount),
+    }
+}
+
+/// Check the fee of a component pass against the fee caps of a derived pass, given the total already charged
+/// for the other component passes. A fee exceeding the per-component cap, or the remainder of the total cap, is an error.
+/// The fee is never reduced, as it belongs to the gatekeeper, not the derived pass authority.
+pub fn check_fee_caps(
+    amount: u64,
+    caps: &Option<FeeCaps>,
+    action: Action,
+    total_charged: u64,
+) -> Result<(), Error> {
+    let caps = match caps {
+        Some(caps) => caps,
+        None => return Ok(()),
+    };
+    let (total_cap, component_cap) = match action {
+        Action::Issue => (caps.max_issue_total, caps.max_issue_per_component),
+        Action::Refresh => (caps.max_refresh_total, caps.max_refresh_per_component),
+    };
+
+    let remaining_total = total_cap.map_or(u64::MAX, |cap| cap.saturating_sub(total_charged));
+    require!(
+        amount <= component_cap.unwrap_or(u64::MAX) && amount <= remaining_total,
+        ErrorCode::FeeCapExceeded
+    );
+    Ok(())
+}
+
+/// When several derived passes are issued at once, waive the fees with a batch discount (see Fee::batch_discount)
+/// that have already been charged for a component pass for an earlier derived pass,
+/// and mark those charged for the first time, so that they are charged once for the whole batch.
+pub fn waive_batch_fees<'a, 'b>(
+    mut gateway_tokens_with_fee: Vec<ParsedGatewayTokenAccountWithFee<'a, 'b>>,
+    batch_fees_charged: &mut [bool],
+) -> Vec<ParsedGatewayTokenAccountWithFee<'a, 'b>> {
+    gateway_tokens_with_fee
+        .iter_mut()
+        .zip(batch_fees_charged.iter_mut())
+        .filter(|((_, _, fee, _), _)| matches!(fee, Some(fee) if fee.batch_discount))
+        .for_each(|((_, _, fee, _), charged)| {
+            if *charged {
+                *fee = None;
+            } else {
+                *charged = true;
+            }
+        });
+
+    gateway_tokens_with_fee
+}
+
+/// Return the gatekeeper networks of any valid gateway tokens held by the recipient in the shared accounts.
+/// These are used to apply fee discounts (see FeeDiscount).
+/// Tokens that are not valid for the recipient (e.g. expired, frozen, or held by another wallet) are skipped,
+/// so that a stale discount pass does not prevent the issue or refresh, it just does not earn the discount.
+pub fn get_discount_networks(shared_accounts: &[AccountInfo], recipient: &Pubkey) -> Vec<Pubkey> {
+    shared_accounts
+        .iter()
+        .filter(|account_info| *account_info.owner == crate::Gateway::id())
+        .filter_map(|account_info| {
+            let gateway_token = Gateway::parse_gateway_token(account_info).ok()?;
+            let balance: u64 = account_info.lamports.borrow().as_();
+            Gateway::verify_gateway_token(
+                &gateway_token,
+                recipient,
+                &gateway_token.gatekeeper_network,
+                balance,
+                None,
+            )
+            .ok()?;
+
+            Some(gateway_token.gatekeeper_network)
+        })
+        .collect()
 }
 
 /// Given a vector of gateway tokens (GTs) with associated fees and gatekeeper (GK) account objects
-/// Returns a map from the gatekeeper key to the lamports (only SOL supported so far) to be sent to it
+/// Returns the breakdown of fees, with one entry per GK and gatekeeper network, in the order the GTs were passed in,
+/// and the lamports (only SOL supported so far) to be sent to each GK.
 /// Unlike the input, which has a separate entry per GT, even if several (or all) are issued by the same GK,
-/// The output has a single entry per GK, referencing the first AccountInfo that points to it.
+/// the payments have a single entry per GK, referencing the first AccountInfo that points to it.
+/// If a GK's fee is split between several recipients, each recipient receives its share in the payments,
+/// while the breakdown has the total for the GK.
+/// The fee adjustments (holder discounts, voucher and fee caps) are applied to each fee in turn.
+/// Both are ordered by first appearance in the input, so that logs and compute usage are reproducible.
 pub fn fee_per_gatekeeper<'a, 'b>(
     gateway_tokens_with_fee: Vec<ParsedGatewayTokenAccountWithFee<'a, 'b>>,
+    adjustments: &FeeAdjustments,
     action: Action,
-) -> HashMap<Pubkey, (&'b AccountInfo<'a>, u64)> {
-    let mut fee_map: HashMap<Pubkey, (&'b AccountInfo<'a>, u64)> = HashMap::new();
+) -> Result<FeeBreakdown<'a, 'b>, Error> {
+    let mut total_charged: u64 = 0;
+    let mut breakdown: Vec<FeePayment> = vec![];
+    let mut payments: Vec<(&'b AccountInfo<'a>, u64)> = vec![];
 
-    let get_amount = |f: Fee| match action {
+    let get_amount = |f: &Fee| match action {
         Action::Issue => f.issue_amount,
         Action::Refresh => f.refresh_amount,
     };
 
-    for (_, _, fee, gatekeeper) in gateway_tokens_with_fee {
-        let current_entry = fee_map.entry(gatekeeper.key()).or_insert((gatekeeper, 0));
-        current_entry.1 += fee.map(get_amount).unwrap_or(0);
+    for (gateway_token, _, fee, payees) in gateway_tokens_with_fee {
+        // the same gatekeeper network and gatekeeper is only charged once,
+        // even if more than one matching gateway token is passed
+        let already_charged = breakdown.iter().any(|entry| {
+            entry.gatekeeper == gateway_token.issuing_gatekeeper
+                && entry.gatekeeper_network == gateway_token.gatekeeper_network
+        });
+        if already_charged {
+            continue;
+        }
+
+        let amount = match &fee {
+            Some(fee) => apply_discount(
+                get_amount(fee),
+                &fee.discount,
+                &adjustments.discount_networks,
+            )?,
+            None => 0,
+        };
+        let amount = apply_voucher(amount, adjustments.voucher, &gateway_token);
+        check_fee_caps(amount, &adjustments.caps, action, total_charged)?;
+        total_charged = total_charged
+            .checked_add(amount)
+            .ok_or_else(|| error!(ErrorCode::PaymentOverflow))?;
+        breakdown.push(FeePayment {
+            gatekeeper: gateway_token.issuing_gatekeeper,
+            gatekeeper_network: gateway_token.gatekeeper_network,
+            action,
+            amount,
+            mint: fee.and_then(|f| f.mint),
+        });
+
+        for (payee, share) in split_amount(amount, &payees)? {
+            match payments
+                .iter_mut()
+                .find(|(account, _)| account.key == payee.key)
+            {
+                Some((_, total)) => {
+                    *total = total
+                        .checked_add(share)
+                        .ok_or_else(|| error!(ErrorCode::PaymentOverflow))?
+                }
+                None => payments.push((payee, share)),
+            }
+        }
     }
 
-    fee_map
+    Ok((breakdown, payments))
 }
 
 /// Check that each gatekeeper network has a matching gateway token. Errors if either a token is missing or a token is invalid
-/// e.g. not parseable, not currently active, not owned by the expected owner, etc.
+/// e.g. not parseable, not currently active, not owned by one of the expected owners, etc.
 /// Returns the parsed and validated component passes
 pub fn get_validated_component_passes<'a, 'b, 'c>(
-    accounts: &'c [AccountInfo<'b>],
+    components: &[ComponentAccounts<'b, 'c>],
+    shared_accounts: &[AccountInfo<'b>],
     gatekeeper_networks: &'a [Pubkey],
-    expected_owner: &'a Pubkey,
+    expected_owners: &'a [Pubkey],
     fee_bumps: &[u8],
+    derived_pass: &Pubkey,
+    properties: &DerivedPassProperties,
 ) -> Result<Vec<ParsedGatewayTokenAccountWithFee<'b, 'c>>, Error> {
-    let parsed_gateway_tokens_with_fee = parse_accounts(accounts, fee_bumps)?;
+    let parsed_gateway_tokens_with_fee =
+        parse_accounts(components, shared_accounts, fee_bumps, Some(derived_pass))?;
 
+    validate_component_passes(
+        &parsed_gateway_tokens_with_fee,
+        gatekeeper_networks,
+        expected_owners,
+        properties,
+    )?;
+
+    Ok(parsed_gateway_tokens_with_fee)
+}
+
+/// Check that each gatekeeper network has a matching gateway token among the already-parsed component passes.
+pub fn validate_component_passes(
+    parsed_gateway_tokens_with_fee: &[ParsedGatewayTokenAccountWithFee],
+    gatekeeper_networks: &[Pubkey],
+    expected_owners: &[Pubkey],
+    properties: &DerivedPassProperties,
+) -> Result<(), Error> {
     gatekeeper_networks
         .iter()
         .try_for_each(|gatekeeper_network| {
             check_has_matching_gateway_token(
-                &parsed_gateway_tokens_with_fee,
+                parsed_gateway_tokens_with_fee,
                 gatekeeper_network,
-                expected_owner,
+                expected_owners,
+                properties,
             )
-        })?;
+        })
+}
 
-    Ok(parsed_gateway_tokens_with_fee)
+/// Return the wallets whose component passes may be used to issue a derived pass to the recipient.
+/// This is the recipient itself, plus the source wallet of the wallet link, if one is passed in the shared accounts.
+/// If a wallet link is passed, it must link to the recipient.
+pub fn get_component_pass_owners(
+    recipient: &Pubkey,
+    shared_accounts: &[AccountInfo],
+    allow_linked_wallets: bool,
+) -> Result<Vec<Pubkey>, Error> {
+    let mut owners = vec![*recipient];
+
+    let wallet_link = shared_accounts
+        .iter()
+        .find_map(|account_info| Account::<WalletLink>::try_from(account_info).ok());
+
+    if let Some(wallet_link) = wallet_link {
+        require!(allow_linked_wallets, ErrorCode::LinkedWalletsDisabled);
+        require!(
+            wallet_link.linked_wallet == *recipient,
+            ErrorCode::InvalidWalletLink
+        );
+
+        msg!(
+            "Accepting component passes from linked wallet {}",
+            wallet_link.source_wallet
+        );
+        owners.push(wallet_link.source_wallet);
+    }
+
+    Ok(owners)
 }
 
 pub fn validate_empty(
@@ -204,16 +837,53 @@ pub fn validate_empty(
     Ok(())
 }
 
+/// Check that the gateway token to be issued does not exist yet.
+/// A revoked gateway token is never removed, so its owner cannot be issued the derived pass again.
+pub fn validate_new_gateway_token(
+    account: &AccountInfo,
+    system_program: &Program<System>,
+) -> Result<(), Error> {
+    if *account.owner == crate::Gateway::id() {
+        let gateway_token = Gateway::parse_gateway_token(account)
+            .map_err(|_| error!(ErrorCode::InvalidGatewayToken))?;
+        require!(
+            gateway_token.state != GatewayTokenState::Revoked,
+            ErrorCode::PassRevoked
+        );
+    }
+    validate_empty(account, system_program)
+}
+
+/// Check that the account is an existing gateway token in the given gatekeeper network, held by the owner, and return it.
+/// If a refresh window is set, the gateway token must expire within the window (or have expired already).
 pub fn validate_gateway_token(
     account: &AccountInfo,
     gateway_program: &Program<crate::Gateway>,
-) -> Result<(), Error> {
+    gatekeeper_network: &Pubkey,
+    owner: &Pubkey,
+    refresh_window: Option<i64>,
+) -> Result<GatewayToken, Error> {
     require!(!account.data_is_empty(), ErrorCode::InvalidGatewayToken);
     require!(
         account.owner == gateway_program.key,
         ErrorCode::InvalidGatewayToken
     );
-    Ok(())
+    let gateway_token = Gateway::parse_gateway_token(account)
+        .map_err(|_| error!(ErrorCode::InvalidGatewayToken))?;
+    require!(
+        gateway_token.gatekeeper_network == *gatekeeper_network
+            && gateway_token.owner_wallet == *owner,
+        ErrorCode::InvalidGatewayToken
+    );
+
+    if let (Some(refresh_window), Some(expire_time)) = (refresh_window, gateway_token.expire_time) {
+        require!(
+            Clock::get()?.unix_timestamp >= expire_time.saturating_sub(refresh_window),
+            ErrorCode::OutsideRefreshWindow
+        );
+    }
+
+    Ok(gateway_token)
 }
 
 pub fn derive_fee_address(
@@ -233,26 +903,22 @@ pub fn derive_fee_address(
     .map_err(|_| error!(ErrorCode::InvalidFeeAccount))
 }
 
-pub enum Action {
-    Issue,
-    Refresh,
-}
-
 /// Given a list of gateway tokens with their associated fees and gatekeeper accounts, pay each gatekeeper for their usage
 /// bearing in mind that several gateway tokens may have been issued
+/// Returns the breakdown of the fees paid
 pub fn pay_gatekeepers<'a, 'b>(
     payer: &mut Signer<'a>,
     parsed_gateway_tokens: Vec<ParsedGatewayTokenAccountWithFee<'a, 'b>>,
+    adjustments: &FeeAdjustments,
     system_program: &AccountInfo<'a>,
     action: Action,
-) -> Result<u64, Error> {
-    let fee_map: HashMap<Pubkey, (&'b AccountInfo<'a>, u64)> =
-        fee_per_gatekeeper(parsed_gateway_tokens, action);
-    let mut total_fee = 0;
+) -> Result<Vec<FeePayment>, Error> {
+    let (breakdown, payments) = fee_per_gatekeeper(parsed_gateway_tokens, adjustments, action)?;
 
-    fee_map
+    payments
         .iter()
-        .try_for_each::<_, Result<(), Error>>(|(_, (gatekeeper, fee))| {
+        .filter(|(_, fee)| *fee > 0)
+        .try_for_each::<_, Result<(), Error>>(|(gatekeeper, fee)| {
             let account_infos = &[
                 payer.to_account_info(),
                 (*gatekeeper).clone().to_account_info(),
@@ -269,15 +935,87 @@ pub fn pay_gatekeepers<'a, 'b>(
                 account_infos,
             )?;
 
-            total_fee += fee;
-
             Ok(())
         })?;
 
-    Ok(total_fee)
+    Ok(breakdown)
 }
 
-pub fn get_expiry_time(expire_duration: Option<i64>) -> Option<UnixTimestamp> {
-    let time_now = Clock::get().unwrap().unix_timestamp;
-    expire_duration.map(|duration| time_now + duration)
+/// Create an account owned by this program at a PDA, paid for by the payer.
+/// If the address has already been funded (e.g. by a third party attempting to block its creation),
+/// the account is topped up to be rent-exempt, allocated and assigned instead.
+pub fn create_pda_account<'a>(
+    payer: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+    space: usize,
+    signer_seeds: &[&[u8]],
+    system_program: &AccountInfo<'a>,
+) -> Result<(), Error> {
+    let required_lamports = Rent::get()?
+        .minimum_balance(space)
+        .saturating_sub(account.lamports());
+
+    if account.lamports() == 0 {
+        invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                account.key,
+                required_lamports,
+                space as u64,
+                &id(),
+            ),
+            &[payer.clone(), account.clone(), system_program.clone()],
+            &[signer_seeds],
+        )?;
+        return Ok(());
+    }
+
+    if required_lamports > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, required_lamports),
+            &[payer.clone(), account.clone(), system_program.clone()],
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(account.key, space as u64),
+        &[account.clone(), system_program.clone()],
+        &[signer_seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(account.key, &id()),
+        &[account.clone(), system_program.clone()],
+        &[signer_seeds],
+    )?;
+
+    Ok(())
+}
+
+/// The expiry time of a derived pass issued or refreshed now.
+/// This is the expire_duration from now, or the fixed expire_at time, whichever is earlier.
+/// On refresh, current_expire_time is the expiry time of the gateway token, which the expire_duration
+/// is added to instead, if the refresh extension of the derived pass is FromExpiry.
+pub fn get_expiry_time(
+    properties: &DerivedPassProperties,
+    current_expire_time: Option<UnixTimestamp>,
+) -> Result<Option<UnixTimestamp>, Error> {
+    let time_now = Clock::get()?.unix_timestamp;
+    if let Some(expire_at) = properties.expire_at {
+        require!(expire_at > time_now, ErrorCode::DerivedPassExpired);
+    }
+
+    let rolling_expiry = properties.expire_duration.map(|duration| {
+        match (properties.refresh_extension, current_expire_time) {
+            (RefreshExtension::FromExpiry { max_horizon }, Some(current_expire_time)) => {
+                current_expire_time
+                    .max(time_now)
+                    .saturating_add(duration)
+                    .min(time_now.saturating_add(max_horizon))
+            }
+            _ => time_now + duration,
+        }
+    });
+    Ok(match (rolling_expiry, properties.expire_at) {
+        (Some(rolling_expiry), Some(expire_at)) => Some(rolling_expiry.min(expire_at)),
+        (rolling_expiry, expire_at) => rolling_expiry.or(expire_at),
+    })
 }
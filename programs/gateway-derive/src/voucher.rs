@@ -0,0 +1,181 @@
+use crate::{id, util::create_pda_account, AccountInfo, Action, ErrorCode, FeeVoucher, Pubkey};
+use anchor_lang::{
+    error,
+    error::Error,
+    prelude::msg,
+    prelude::{Program, Signer, System},
+    require,
+    solana_program::{
+        clock::Clock,
+        ed25519_program,
+        sysvar::{
+            self,
+            instructions::{load_current_index_checked, load_instruction_at_checked},
+            Sysvar,
+        },
+    },
+    AnchorSerialize, ToAccountInfo,
+};
+use solana_gateway::state::GatewayToken;
+
+pub(crate) const VOUCHER_SEED: &[u8; 27] = br"gateway_derive_voucher_seed";
+
+/// Prepended to the serialized voucher to form the signed message,
+/// so that a voucher signature cannot be mistaken for a signature over anything else.
+pub const VOUCHER_MESSAGE_PREFIX: &[u8; 22] = br"gateway_derive_voucher";
+
+// The layout of the ed25519 program instruction data:
+// [num_signatures: u8, padding: u8, offsets: [Ed25519SignatureOffsets; num_signatures], ...data]
+const ED25519_OFFSETS_START: usize = 2;
+const ED25519_OFFSETS_SIZE: usize = 14;
+const ED25519_PUBKEY_SIZE: usize = 32;
+// An instruction index of u16::MAX refers to the ed25519 instruction itself
+const ED25519_CURRENT_INSTRUCTION: u16 = u16::MAX;
+
+/// The message that the voucher signer signs
+pub fn get_voucher_message(voucher: &FeeVoucher) -> Result<Vec<u8>, Error> {
+    let mut message = VOUCHER_MESSAGE_PREFIX.to_vec();
+    voucher
+        .serialize(&mut message)
+        .map_err(|_| error!(ErrorCode::InvalidVoucher))?;
+    Ok(message)
+}
+
+/// Check whether an ed25519 program instruction verifies a signature by the signer over the message.
+/// All signature data must be contained in the instruction itself.
+fn ed25519_instruction_verifies(data: &[u8], signer: &Pubkey, message: &[u8]) -> bool {
+    let read_u16 = |offset: usize| -> Option<u16> {
+        data.get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    let num_signatures = match data.first() {
+        Some(num_signatures) => *num_signatures as usize,
+        None => return false,
+    };
+
+    (0..num_signatures).any(|i| {
+        let start = ED25519_OFFSETS_START + i * ED25519_OFFSETS_SIZE;
+        let offsets = (0..7)
+            .map(|field| read_u16(start + 2 * field))
+            .collect::<Option<Vec<u16>>>();
+        let (pubkey_offset, message_offset, message_size) = match offsets.as_deref() {
+            Some(
+                [_, signature_instruction, pubkey_offset, pubkey_instruction, message_offset, message_size, message_instruction],
+            ) if *signature_instruction == ED25519_CURRENT_INSTRUCTION
+                && *pubkey_instruction == ED25519_CURRENT_INSTRUCTION
+                && *message_instruction == ED25519_CURRENT_INSTRUCTION =>
+            {
+                (
+                    *pubkey_offset as usize,
+                    *message_offset as usize,
+                    *message_size as usize,
+                )
+            }
+            _ => return false,
+        };
+
+        data.get(pubkey_offset..pubkey_offset + ED25519_PUBKEY_SIZE) == Some(signer.as_ref())
+            && data.get(message_offset..message_offset + message_size) == Some(message)
+    })
+}
+
+/// Check that an earlier instruction in the transaction is an ed25519 program instruction
+/// verifying the signature of the voucher signer over the voucher.
+/// The ed25519 program fails the transaction if the signature is invalid, so it only remains
+/// to check that the instruction covers the voucher.
+fn verify_voucher_signature(
+    instructions_sysvar: &AccountInfo,
+    voucher: &FeeVoucher,
+) -> Result<(), Error> {
+    let message = get_voucher_message(voucher)?;
+    let current_index = load_current_index_checked(instructions_sysvar)?;
+
+    for index in 0..current_index {
+        let instruction = load_instruction_at_checked(index as usize, instructions_sysvar)?;
+        if instruction.program_id == ed25519_program::id()
+            && ed25519_instruction_verifies(&instruction.data, &voucher.signer, &message)
+        {
+            return Ok(());
+        }
+    }
+
+    Err(error!(ErrorCode::InvalidVoucherSignature))
+}
+
+/// Validate the voucher, verify its signature, and mark it as used by creating the consumed-voucher PDA.
+/// The instructions sysvar and the consumed-voucher PDA must be passed in the shared accounts.
+pub fn redeem_voucher<'a>(
+    voucher: &FeeVoucher,
+    derived_pass: &Pubkey,
+    recipient: &Signer<'a>,
+    action: Action,
+    shared_accounts: &[AccountInfo<'a>],
+    system_program: &Program<'a, System>,
+) -> Result<(), Error> {
+    require!(
+        voucher.recipient == *recipient.key
+            && voucher.derived_pass == *derived_pass
+            && voucher.action == action,
+        ErrorCode::InvalidVoucher
+    );
+    // only the gatekeeper can give away its own fees
+    require!(
+        voucher.signer == voucher.gatekeeper,
+        ErrorCode::InvalidVoucher
+    );
+    if let Some(expire_time) = voucher.expire_time {
+        require!(
+            Clock::get()?.unix_timestamp < expire_time,
+            ErrorCode::VoucherExpired
+        );
+    }
+
+    let instructions_sysvar = shared_accounts
+        .iter()
+        .find(|account_info| sysvar::instructions::check_id(account_info.key))
+        .ok_or_else(|| error!(ErrorCode::InvalidVoucherSignature))?;
+    verify_voucher_signature(instructions_sysvar, voucher)?;
+
+    let id_bytes = voucher.id.to_le_bytes();
+    let (voucher_address, bump) =
+        Pubkey::find_program_address(&[VOUCHER_SEED, voucher.signer.as_ref(), &id_bytes], &id());
+    let voucher_account = shared_accounts
+        .iter()
+        .find(|account_info| *account_info.key == voucher_address)
+        .ok_or_else(|| error!(ErrorCode::InvalidVoucher))?;
+    require!(
+        *voucher_account.owner != id(),
+        ErrorCode::VoucherAlreadyUsed
+    );
+
+    msg!(
+        "Redeeming voucher {} signed by {}",
+        voucher.id,
+        voucher.signer
+    );
+    create_pda_account(
+        &recipient.to_account_info(),
+        voucher_account,
+        0,
+        &[VOUCHER_SEED, voucher.signer.as_ref(), &id_bytes, &[bump]],
+        &system_program.to_account_info(),
+    )
+}
+
+/// Reduce a fee by the voucher amount, if the voucher applies to the gatekeeper and network of the component pass
+pub fn apply_voucher(
+    amount: u64,
+    voucher: Option<&FeeVoucher>,
+    gateway_token: &GatewayToken,
+) -> u64 {
+    match voucher {
+        Some(voucher)
+            if voucher.gatekeeper == gateway_token.issuing_gatekeeper
+                && (voucher.gatekeeper_network.is_none()
+                    || voucher.gatekeeper_network == Some(gateway_token.gatekeeper_network)) =>
+        {
+            amount.saturating_sub(voucher.amount)
+        }
+        _ => amount,
+    }
+}
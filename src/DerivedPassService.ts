@@ -1,19 +1,41 @@
-import { AnchorProvider, Program, web3, BN } from "@project-serum/anchor";
 import {
-  calculateDerivedPassSize,
+  AnchorProvider,
+  EventParser,
+  IdlEvents,
+  IdlTypes,
+  Program,
+  web3,
+  BN,
+} from "@project-serum/anchor";
+import {
   deriveGatekeeper,
   deriveGatekeeperAccount,
+  deriveFeeOverrideAddress,
+  deriveFeeVaultAddress,
   deriveGatekeeperFeeAddress,
+  deriveNetworkFeeAddress,
+  deriveRecipientStateAddress,
+  deriveStatsAddress,
+  deriveVoucherAddress,
+  deriveWalletLinkAddress,
   FeeType,
   feeTypeToInt,
   fetchProgram,
   findComponentPassesForDerivedPass,
   toAccountMeta,
   toSimpleAccountMeta,
+  VOUCHER_MESSAGE_PREFIX,
 } from "./lib/util";
+import {
+  FeeAccount,
+  fetchDerivedPass,
+  fetchFeeNullable,
+} from "./lib/migration";
 import { GatewayDerive } from "../target/types/gateway_derive";
 import * as anchor from "@project-serum/anchor";
 import {
+  findGatewayToken,
+  GatewayToken,
   getFeatureAccountAddress,
   getGatewayTokenAddressForOwnerAndGatekeeperNetwork,
   NetworkFeature,
@@ -21,6 +43,7 @@ import {
   UserTokenExpiry,
 } from "@identity.com/solana-gateway-ts";
 import { PublicKey, Transaction } from "@solana/web3.js";
+import { chain, uniqBy, unnest } from "ramda";
 
 // TODO remove once Anchor cleans up its Wallet interface
 /**
@@ -39,6 +62,151 @@ export type Properties = {
   expireDuration?: number;
   expireOnUse?: boolean;
   refreshDisabled?: boolean;
+  allowLinkedWallets?: boolean;
+  feeCaps?: FeeCaps;
+  // the maximum number of times the derived pass can be issued
+  maxSupply?: number;
+  // the maximum number of times the derived pass can be issued in each window of windowDuration seconds
+  maxIssuesPerWindow?: { windowDuration: number; maxIssues: number };
+  // the minimum number of seconds between refreshes of a recipient's derived pass
+  minRefreshInterval?: number;
+  // the maximum number of times a recipient's derived pass can be refreshed after each issue
+  maxRefreshes?: number;
+  // if true, each component pass must have an expiry time
+  requireComponentExpiry?: boolean;
+  // the minimum number of seconds that each component pass must remain valid for on issue or refresh
+  minComponentValidity?: number;
+  // the unix timestamps between which the derived pass can be issued
+  issueNotBefore?: number;
+  issueNotAfter?: number;
+  // if true, the derived pass can only be refreshed between issueNotBefore and issueNotAfter too
+  issueWindowAppliesToRefresh?: boolean;
+  // a unix timestamp at which the derived pass expires (the earlier of this and expireDuration applies)
+  expireAt?: number;
+  // if set, the derived pass can only be refreshed in this many seconds before it expires, or after it has expired
+  refreshWindow?: number;
+  // by default, a refresh sets the expiry to expireDuration after the refresh.
+  // Set this to extend the current expiry by expireDuration instead, up to maxHorizon seconds after the refresh
+  refreshFromExpiry?: { maxHorizon: number };
+};
+
+/**
+ * Limits on the component pass fees paid on issue or refresh of a derived pass,
+ * applied after any discounts and vouchers. An issue or refresh exceeding a cap fails.
+ */
+export type FeeCaps = {
+  maxIssueTotal?: number;
+  maxRefreshTotal?: number;
+  maxIssuePerComponent?: number;
+  maxRefreshPerComponent?: number;
+};
+
+const toOptionalBN = (value?: number) =>
+  value === undefined ? null : new BN(value);
+
+/**
+ * Convert the properties of a derived pass into the form expected by the initialize instruction
+ */
+export const toDerivedPassProperties = (properties: Properties) => {
+  const expireDurationBN = properties.expireDuration
+    ? new BN(properties.expireDuration)
+    : null;
+  const feeCaps = properties.feeCaps
+    ? {
+        maxIssueTotal: toOptionalBN(properties.feeCaps.maxIssueTotal),
+        maxRefreshTotal: toOptionalBN(properties.feeCaps.maxRefreshTotal),
+        maxIssuePerComponent: toOptionalBN(
+          properties.feeCaps.maxIssuePerComponent
+        ),
+        maxRefreshPerComponent: toOptionalBN(
+          properties.feeCaps.maxRefreshPerComponent
+        ),
+      }
+    : null;
+  const maxIssuesPerWindow = properties.maxIssuesPerWindow
+    ? {
+        windowDuration: new BN(properties.maxIssuesPerWindow.windowDuration),
+        maxIssues: properties.maxIssuesPerWindow.maxIssues,
+      }
+    : null;
+  return {
+    expireOnUse: false,
+    refreshDisabled: false,
+    allowLinkedWallets: false,
+    requireComponentExpiry: false,
+    issueWindowAppliesToRefresh: false,
+    ...properties,
+    expireDuration: expireDurationBN,
+    feeCaps,
+    maxSupply: toOptionalBN(properties.maxSupply),
+    maxIssuesPerWindow,
+    minRefreshInterval: toOptionalBN(properties.minRefreshInterval),
+    maxRefreshes:
+      properties.maxRefreshes === undefined ? null : properties.maxRefreshes,
+    minComponentValidity: toOptionalBN(properties.minComponentValidity),
+    issueNotBefore: toOptionalBN(properties.issueNotBefore),
+    issueNotAfter: toOptionalBN(properties.issueNotAfter),
+    expireAt: toOptionalBN(properties.expireAt),
+    refreshWindow: toOptionalBN(properties.refreshWindow),
+    refreshExtension: properties.refreshFromExpiry
+      ? {
+          fromExpiry: {
+            maxHorizon: new BN(properties.refreshFromExpiry.maxHorizon),
+          },
+        }
+      : { fromNow: {} },
+  };
+};
+
+export type FeesPaid = IdlEvents<GatewayDerive>["FeesPaid"];
+
+/**
+ * A discount on a fee for recipients holding a pass on the given gatekeeper network.
+ * The percentage (0-100) is deducted first, followed by the fixed amount.
+ */
+export type FeeDiscount = {
+  gatekeeperNetwork: web3.PublicKey;
+  percentage?: number;
+  amount?: number;
+};
+
+/**
+ * Where the fees of a gatekeeper are paid.
+ * If escrow is true, fees accrue in a fee vault, and are withdrawn by the gatekeeper using claimFees.
+ * If a treasury is set, fees are paid (or claimed) to the treasury instead of the gatekeeper.
+ * If splits are set, fees are shared between the split recipients instead.
+ * The splits must sum to 10,000 basis points (100%), and cannot be combined with a treasury.
+ */
+export type FeeRouting = {
+  escrow?: boolean;
+  treasury?: web3.PublicKey;
+  splits?: FeeSplit[];
+};
+
+export type FeeSplit = {
+  recipient: web3.PublicKey;
+  basisPoints: number;
+};
+
+/**
+ * A one-off voucher reducing (or, with amount = u64::MAX, waiving) a gatekeeper's fees
+ * for a single issue or refresh of a derived pass.
+ * It must be signed by the gatekeeper whose fees it reduces.
+ */
+export type FeeVoucher = IdlTypes<GatewayDerive>["FeeVoucher"];
+
+/**
+ * A voucher with the ed25519 signature of its signer over the voucher message (see getVoucherMessage)
+ */
+export type SignedFeeVoucher = {
+  voucher: FeeVoucher;
+  signature: Uint8Array;
+};
+
+type ComponentPassAccounts = {
+  remainingAccounts: web3.AccountMeta[];
+  feeAddressBumps: Buffer;
+  componentAccountCounts: Buffer;
 };
 
 export class DerivedPassService {
@@ -61,6 +229,195 @@ export class DerivedPassService {
     );
   }
 
+  /**
+   * Get the remaining accounts needed to validate and pay for the component passes,
+   * in the grouped account layout, along with the instruction arguments describing them.
+   * Any fee overrides for the derived passes are included.
+   */
+  private async getComponentPassAccounts(
+    componentPasses: GatewayToken[],
+    recipient: web3.PublicKey,
+    sourceWallet?: web3.PublicKey,
+    derivedPasses: web3.PublicKey[] = []
+  ): Promise<ComponentPassAccounts> {
+    // Each component pass is associated with a fee account (it may be empty) and a gatekeeper account (to receive payment)
+    // Note: The gatekeepers may be duplicated here, if the same gatekeeper issues more than one component pass.
+    // This is handled in the program.
+    const groups = await Promise.all(
+      componentPasses.map(async (pass) => {
+        const [feeAddress, feeAddressBump] =
+          await deriveGatekeeperFeeAddress(
+            pass.issuingGatekeeper,
+            pass.gatekeeperNetwork,
+            this.program
+          );
+        const genericFee = await fetchFeeNullable(this.program, feeAddress);
+        // a fee override for a derived pass takes precedence over the generic fee
+        const feeOverrides = (
+          await Promise.all(
+            derivedPasses.map(async (derivedPass) => {
+              const [feeOverrideAddress] = await deriveFeeOverrideAddress(
+                pass.issuingGatekeeper,
+                pass.gatekeeperNetwork,
+                derivedPass,
+                this.program
+              );
+              const feeOverride = await fetchFeeNullable(
+                this.program,
+                feeOverrideAddress
+              );
+              return feeOverride
+                ? { address: feeOverrideAddress, fee: feeOverride }
+                : null;
+            })
+          )
+        ).filter(Boolean) as { address: web3.PublicKey; fee: FeeAccount }[];
+        // the default fee of the gatekeeper network applies if the gatekeeper has not set a fee
+        // (and a derived pass has no override). In that case, the network fee account must be passed
+        // in the shared accounts, even if empty
+        const everyDerivedPassOverridden =
+          derivedPasses.length > 0 &&
+          feeOverrides.length === derivedPasses.length;
+        const [networkFeeAddress] =
+          genericFee || everyDerivedPassOverridden
+            ? [null]
+            : await deriveNetworkFeeAddress(
+                pass.gatekeeperNetwork,
+                this.program
+              );
+        const networkFee = networkFeeAddress
+          ? await fetchFeeNullable(this.program, networkFeeAddress)
+          : null;
+        // the payees are those of the first override, if any. When issuing several derived passes at once,
+        // their fees for a component pass must be routed in the same way
+        const fee = feeOverrides[0]?.fee || genericFee || networkFee;
+        // escrowed fees are paid into the gatekeeper's fee vault,
+        // otherwise to the gatekeeper's treasury, if it has one
+        const [payee] = fee?.routing.escrow
+          ? await deriveFeeVaultAddress(
+              pass.issuingGatekeeper,
+              pass.gatekeeperNetwork,
+              this.program
+            )
+          : [fee?.routing.treasury || pass.issuingGatekeeper];
+        // split fees are paid directly to the split recipients, passed after the payee
+        const splitRecipients =
+          fee && !fee.routing.escrow && !fee.routing.treasury
+            ? fee.routing.splits.map((split) => split.recipient)
+            : [];
+        const accounts = [
+          toSimpleAccountMeta(pass.publicKey),
+          toSimpleAccountMeta(feeAddress),
+          // pass the payee accounts as writeable so that they can receive payment
+          ...[payee, ...splitRecipients].map(toAccountMeta(false, true)),
+          ...feeOverrides.map(({ address }) => toSimpleAccountMeta(address)),
+        ];
+        return {
+          accounts,
+          feeAddressBump,
+          discount: fee?.discount,
+          networkFeeAddress,
+        };
+      })
+    );
+
+    const remainingAccounts = chain(({ accounts }) => accounts, groups);
+
+    uniqBy(
+      (address) => address.toBase58(),
+      groups
+        .map(({ networkFeeAddress }) => networkFeeAddress)
+        .filter(Boolean) as web3.PublicKey[]
+    ).forEach((address) =>
+      remainingAccounts.push(toSimpleAccountMeta(address))
+    );
+
+    // If any of the fees offer a discount to holders of a pass, and the recipient holds it,
+    // pass it in the shared accounts after the component pass accounts
+    const discountNetworks = uniqBy(
+      (network) => network.toBase58(),
+      groups
+        .map(({ discount }) => discount?.gatekeeperNetwork)
+        .filter(Boolean) as web3.PublicKey[]
+    );
+    const discountPasses = await Promise.all(
+      discountNetworks.map((network) =>
+        findGatewayToken(this.provider.connection, recipient, network)
+      )
+    );
+    discountPasses
+      .filter(Boolean)
+      .forEach((pass) =>
+        remainingAccounts.push(
+          toSimpleAccountMeta((pass as GatewayToken).publicKey)
+        )
+      );
+
+    // The wallet link, if used, is passed in the shared accounts
+    if (sourceWallet) {
+      const [walletLink] = await deriveWalletLinkAddress(
+        sourceWallet,
+        recipient,
+        this.program
+      );
+      remainingAccounts.push(toSimpleAccountMeta(walletLink));
+    }
+
+    return {
+      remainingAccounts,
+      // pass the fee address derivation bumps so that the derivation can be checked on the program
+      feeAddressBumps: Buffer.from(groups.map((g) => g.feeAddressBump)),
+      componentAccountCounts: Buffer.from(
+        groups.map((g) => g.accounts.length)
+      ),
+    };
+  }
+
+  /**
+   * Get the message that the signer of a voucher must sign.
+   */
+  getVoucherMessage(voucher: FeeVoucher): Buffer {
+    return Buffer.concat([
+      Buffer.from(VOUCHER_MESSAGE_PREFIX),
+      this.program.coder.types.encode("FeeVoucher", voucher),
+    ]);
+  }
+
+  /**
+   * Get the instruction verifying the voucher signature, which must precede the issue or refresh instruction,
+   * and the shared accounts needed to redeem the voucher.
+   */
+  private async getVoucherAccounts(
+    signedVoucher?: SignedFeeVoucher
+  ): Promise<{
+    remainingAccounts: web3.AccountMeta[];
+    preInstructions: web3.TransactionInstruction[];
+  }> {
+    if (!signedVoucher) return { remainingAccounts: [], preInstructions: [] };
+
+    const { voucher, signature } = signedVoucher;
+    const [voucherAddress] = await deriveVoucherAddress(
+      voucher.signer,
+      voucher.id,
+      this.program
+    );
+
+    return {
+      remainingAccounts: [
+        toSimpleAccountMeta(web3.SYSVAR_INSTRUCTIONS_PUBKEY),
+        // the voucher account is created to mark the voucher as used
+        toAccountMeta(false, true)(voucherAddress),
+      ],
+      preInstructions: [
+        web3.Ed25519Program.createInstructionWithPublicKey({
+          publicKey: voucher.signer.toBytes(),
+          message: this.getVoucherMessage(voucher),
+          signature,
+        }),
+      ],
+    };
+  }
+
   async derivePass(
     sourcePassTypes: web3.PublicKey[],
     properties: Properties = {}
@@ -76,29 +433,24 @@ export class DerivedPassService {
       derivedGatekeeper,
       derivedPass.publicKey
     );
+    const [stats] = await deriveStatsAddress(
+      derivedPass.publicKey,
+      this.program
+    );
 
     const accounts = {
       derivedPass: derivedPass.publicKey,
       authority,
       derivedGatekeeper,
       derivedGatekeeperAccount,
+      stats,
       gatewayProgram: GATEWAY_PROGRAM_ID,
       systemProgram: anchor.web3.SystemProgram.programId,
     };
 
-    const expireDurationBN = properties.expireDuration
-      ? new BN(properties.expireDuration)
-      : null;
-    const derivePassProperties = {
-      expireOnUse: false,
-      refreshDisabled: false,
-      ...properties,
-      expireDuration: expireDurationBN,
-    };
-
     const remainingAccounts = [];
     if (properties.expireOnUse) {
-      if (!properties.expireDuration)
+      if (!properties.expireDuration && !properties.expireAt)
         throw new Error("Only expirable tokens can be marked as expireOnUse");
 
       const feature = new NetworkFeature({
@@ -116,9 +468,9 @@ export class DerivedPassService {
     const transactionSignature = await this.program.methods
       .initialize(
         sourcePassTypes,
-        calculateDerivedPassSize(sourcePassTypes),
+        0, // ignored - the size of the derived pass is calculated by the program
         derivedGatekeeperBump,
-        derivePassProperties
+        toDerivedPassProperties(properties)
       )
       .accounts(accounts)
       .remainingAccounts(remainingAccounts)
@@ -130,9 +482,17 @@ export class DerivedPassService {
     return [transactionSignature, derivedPass.publicKey];
   }
 
+  /**
+   * Issue a derived pass to the provider wallet.
+   * If a source wallet is passed, component passes held by the source wallet are also considered.
+   * The source wallet must have been linked to the provider wallet using linkWallet.
+   * If a signed voucher is passed, it is redeemed to reduce the fees paid.
+   */
   async issue(
     authority: web3.PublicKey,
-    derivedPass: web3.PublicKey
+    derivedPass: web3.PublicKey,
+    sourceWallet?: web3.PublicKey,
+    voucher?: SignedFeeVoucher
   ): Promise<[string, web3.PublicKey]> {
     const recipient = this.provider.wallet.publicKey;
     const gatewayToken =
@@ -149,30 +509,19 @@ export class DerivedPassService {
     const componentPasses = await findComponentPassesForDerivedPass(
       this.program,
       derivedPass,
-      recipient
+      recipient,
+      sourceWallet
     );
 
-    const componentPassAccounts = componentPasses
-      .map((pass) => pass.publicKey)
-      .map(toSimpleAccountMeta);
-
-    const feeAdddressPromises = componentPasses.map((pass) =>
-      deriveGatekeeperFeeAddress(
-        pass.issuingGatekeeper,
-        pass.gatekeeperNetwork,
-        this.program
-      )
+    const [stats, statsBump] = await deriveStatsAddress(
+      derivedPass,
+      this.program
+    );
+    const [recipientState] = await deriveRecipientStateAddress(
+      derivedPass,
+      recipient,
+      this.program
     );
-    const feeAddressesAndBumps = await Promise.all(feeAdddressPromises);
-    const feeAddressAccounts = feeAddressesAndBumps
-      .map(([key]) => key)
-      .map(toSimpleAccountMeta);
-    // pass the fee address derivation bumps so that the derivation can be checked on the program
-    const feeAddressBumps = feeAddressesAndBumps.map(([, bump]) => bump);
-    // pass the gatekeeper accounts as writeable so that they can receive payment
-    const gatekeeperAccounts = componentPasses
-      .map((pass) => pass.issuingGatekeeper)
-      .map(toAccountMeta(false, true));
 
     const accounts = {
       derivedPass,
@@ -180,23 +529,37 @@ export class DerivedPassService {
       derivedGatekeeper,
       derivedGatekeeperAccount,
       gatewayToken,
+      stats,
+      recipientState,
       gatewayProgram: GATEWAY_PROGRAM_ID,
       systemProgram: anchor.web3.SystemProgram.programId,
     };
 
-    // Each component pass is associated with a fee account (it may be empty) and a gatekeeper account (to receive payment)
-    // Note: The gatekeepers may be duplicated here, if the same gatekeeper issues more than one component pass.
-    // This is handled in the program.
-    const remainingAccounts = [
-      ...componentPassAccounts,
-      ...feeAddressAccounts,
-      ...gatekeeperAccounts,
-    ];
+    const {
+      remainingAccounts,
+      feeAddressBumps,
+      componentAccountCounts,
+    } = await this.getComponentPassAccounts(
+      componentPasses,
+      recipient,
+      sourceWallet,
+      [derivedPass]
+    );
+    const voucherAccounts = await this.getVoucherAccounts(voucher);
 
     const transactionSignature = await this.program.methods
-      .issue(Buffer.from(feeAddressBumps))
+      .issue(
+        feeAddressBumps,
+        componentAccountCounts,
+        voucher ? voucher.voucher : null,
+        statsBump
+      )
       .accounts(accounts)
-      .remainingAccounts(remainingAccounts)
+      .remainingAccounts([
+        ...remainingAccounts,
+        ...voucherAccounts.remainingAccounts,
+      ])
+      .preInstructions(voucherAccounts.preInstructions)
       .rpc();
 
     await this.provider.connection.confirmTransaction(transactionSignature);
@@ -204,10 +567,130 @@ export class DerivedPassService {
     return [transactionSignature, gatewayToken];
   }
 
+  /**
+   * Issue several derived passes to the provider wallet in one transaction.
+   * Component passes shared between the derived passes are passed once, and their fees are paid for each
+   * derived pass (including any fee overrides), unless the gatekeeper's fee has a batch discount.
+   * The fees of a gatekeeper for the derived passes must be routed in the same way.
+   * @return the transaction signature and the gateway token for each derived pass
+   */
+  async issueMany(
+    derivedPasses: web3.PublicKey[],
+    sourceWallet?: web3.PublicKey
+  ): Promise<[string, web3.PublicKey[]]> {
+    const recipient = this.provider.wallet.publicKey;
+
+    const derivedPassAccountsAndTokens = await Promise.all(
+      derivedPasses.map(async (derivedPass) => {
+        const { authority } = await fetchDerivedPass(
+          this.program,
+          derivedPass
+        );
+        const gatewayToken =
+          await getGatewayTokenAddressForOwnerAndGatekeeperNetwork(
+            recipient,
+            derivedPass
+          );
+        const [derivedGatekeeper] = await deriveGatekeeper(
+          authority,
+          this.program
+        );
+        const derivedGatekeeperAccount = await deriveGatekeeperAccount(
+          derivedGatekeeper,
+          derivedPass
+        );
+        const [stats, statsBump] = await deriveStatsAddress(
+          derivedPass,
+          this.program
+        );
+        const [recipientState] = await deriveRecipientStateAddress(
+          derivedPass,
+          recipient,
+          this.program
+        );
+
+        // the derived pass, its statistics and the recipient state are writeable so that they can be updated
+        const accounts = [
+          toAccountMeta(false, true)(derivedPass),
+          toAccountMeta(false, true)(gatewayToken),
+          toSimpleAccountMeta(derivedGatekeeper),
+          toSimpleAccountMeta(derivedGatekeeperAccount),
+          toAccountMeta(false, true)(stats),
+          toAccountMeta(false, true)(recipientState),
+        ];
+        return [accounts, gatewayToken, statsBump] as [
+          web3.AccountMeta[],
+          web3.PublicKey,
+          number
+        ];
+      })
+    );
+    const derivedPassAccounts = chain(
+      ([accounts]) => accounts,
+      derivedPassAccountsAndTokens
+    );
+    const gatewayTokens = derivedPassAccountsAndTokens.map(
+      ([, gatewayToken]) => gatewayToken
+    );
+    const statsBumps = derivedPassAccountsAndTokens.map(
+      ([, , statsBump]) => statsBump
+    );
+
+    // find the component passes for each derived pass, and remove those shared between derived passes
+    const componentPassesPerDerivedPass = await Promise.all(
+      derivedPasses.map((derivedPass) =>
+        findComponentPassesForDerivedPass(
+          this.program,
+          derivedPass,
+          recipient,
+          sourceWallet
+        )
+      )
+    );
+    const componentPasses = uniqBy(
+      (pass) => pass.publicKey.toBase58(),
+      unnest(componentPassesPerDerivedPass)
+    );
+
+    const {
+      remainingAccounts: componentPassAccounts,
+      feeAddressBumps,
+      componentAccountCounts,
+    } = await this.getComponentPassAccounts(
+      componentPasses,
+      recipient,
+      sourceWallet,
+      derivedPasses
+    );
+
+    const accounts = {
+      recipient,
+      gatewayProgram: GATEWAY_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+
+    const transactionSignature = await this.program.methods
+      .issueMany(
+        derivedPasses.length,
+        feeAddressBumps,
+        componentAccountCounts,
+        Buffer.from(statsBumps)
+      )
+      .accounts(accounts)
+      .remainingAccounts([...derivedPassAccounts, ...componentPassAccounts])
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return [transactionSignature, gatewayTokens];
+  }
+
   async refresh(
     gatewayToken: web3.PublicKey,
     authority: web3.PublicKey,
-    derivedPass: web3.PublicKey
+    derivedPass: web3.PublicKey,
+    sourceWallet?: web3.PublicKey,
+    voucher?: SignedFeeVoucher
   ): Promise<[string, web3.PublicKey]> {
     const recipient = this.provider.wallet.publicKey;
     const [derivedGatekeeper] = await deriveGatekeeper(authority, this.program);
@@ -219,30 +702,19 @@ export class DerivedPassService {
     const componentPasses = await findComponentPassesForDerivedPass(
       this.program,
       derivedPass,
-      recipient
+      recipient,
+      sourceWallet
     );
 
-    const componentPassAccounts = componentPasses
-      .map((pass) => pass.publicKey)
-      .map(toSimpleAccountMeta);
-
-    const feeAdddressPromises = componentPasses.map((pass) =>
-      deriveGatekeeperFeeAddress(
-        pass.issuingGatekeeper,
-        pass.gatekeeperNetwork,
-        this.program
-      )
+    const [stats, statsBump] = await deriveStatsAddress(
+      derivedPass,
+      this.program
+    );
+    const [recipientState] = await deriveRecipientStateAddress(
+      derivedPass,
+      recipient,
+      this.program
     );
-    const feeAddressesAndBumps = await Promise.all(feeAdddressPromises);
-    const feeAddressAccounts = feeAddressesAndBumps
-      .map(([key]) => key)
-      .map(toSimpleAccountMeta);
-    // pass the fee address derivation bumps so that the derivation can be checked on the program
-    const feeAddressBumps = feeAddressesAndBumps.map(([, bump]) => bump);
-    // pass the gatekeeper accounts as writeable so that they can receive payment
-    const gatekeeperAccounts = componentPasses
-      .map((pass) => pass.issuingGatekeeper)
-      .map(toAccountMeta(false, true));
 
     const accounts = {
       derivedPass,
@@ -250,23 +722,37 @@ export class DerivedPassService {
       gatewayToken,
       derivedGatekeeper,
       derivedGatekeeperAccount,
+      stats,
+      recipientState,
       gatewayProgram: GATEWAY_PROGRAM_ID,
       systemProgram: anchor.web3.SystemProgram.programId,
     };
 
-    // Each component pass is associated with a fee account (it may be empty) and a gatekeeper account (to receive payment)
-    // Note: The gatekeepers may be duplicated here, if the same gatekeeper issues more than one component pass.
-    // This is handled in the program.
-    const remainingAccounts = [
-      ...componentPassAccounts,
-      ...feeAddressAccounts,
-      ...gatekeeperAccounts,
-    ];
+    const {
+      remainingAccounts,
+      feeAddressBumps,
+      componentAccountCounts,
+    } = await this.getComponentPassAccounts(
+      componentPasses,
+      recipient,
+      sourceWallet,
+      [derivedPass]
+    );
+    const voucherAccounts = await this.getVoucherAccounts(voucher);
 
     const transactionSignature = await this.program.methods
-      .refresh(Buffer.from(feeAddressBumps))
+      .refresh(
+        feeAddressBumps,
+        componentAccountCounts,
+        voucher ? voucher.voucher : null,
+        statsBump
+      )
       .accounts(accounts)
-      .remainingAccounts(remainingAccounts)
+      .remainingAccounts([
+        ...remainingAccounts,
+        ...voucherAccounts.remainingAccounts,
+      ])
+      .preInstructions(voucherAccounts.preInstructions)
       .rpc();
 
     await this.provider.connection.confirmTransaction(transactionSignature);
@@ -274,12 +760,115 @@ export class DerivedPassService {
     return [transactionSignature, gatewayToken];
   }
 
+  /**
+   * Get the breakdown of fees paid in an issue, issueMany or refresh transaction.
+   * The breakdown has one entry per gatekeeper and component pass type, in the order that the component passes were passed.
+   * @return the fees paid, or null if the transaction did not pay any fees
+   */
+  async getFeesPaid(transactionSignature: string): Promise<FeesPaid | null> {
+    const transaction = await this.provider.connection.getTransaction(
+      transactionSignature,
+      { commitment: "confirmed" }
+    );
+    const logs = transaction?.meta?.logMessages || [];
+
+    let feesPaid: FeesPaid | null = null;
+    const eventParser = new EventParser(
+      this.program.programId,
+      this.program.coder
+    );
+    eventParser.parseLogs(logs, (event) => {
+      if (event.name === "FeesPaid") feesPaid = event.data as FeesPaid;
+    });
+
+    return feesPaid;
+  }
+
+  /**
+   * Get the instruction to create the provider wallet's fee vault for the gatekeeper network,
+   * if the fee routing escrows fees and the vault does not exist yet.
+   */
+  private async getFeeVaultPreInstructions(
+    gatekeeperNetwork: web3.PublicKey,
+    routing: FeeRouting
+  ): Promise<web3.TransactionInstruction[]> {
+    if (!routing.escrow) return [];
+
+    const [feeVault] = await deriveFeeVaultAddress(
+      this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      this.program
+    );
+    const feeVaultExists = await this.provider.connection.getAccountInfo(
+      feeVault
+    );
+    if (feeVaultExists) return [];
+
+    return [
+      await this.program.methods
+        .createFeeVault()
+        .accounts({
+          feeVault,
+          authority: this.provider.wallet.publicKey,
+          gatekeeperNetwork,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        })
+        .instruction(),
+    ];
+  }
+
+  /**
+   * Convert fee settings into the arguments of the create/update fee instructions
+   */
+  private toFeeArgs(
+    issueFee: number,
+    refreshFee: number,
+    percentage: number,
+    type: FeeType,
+    discount: FeeDiscount | undefined,
+    routing: FeeRouting
+  ) {
+    return [
+      new anchor.BN(issueFee),
+      new anchor.BN(refreshFee),
+      percentage,
+      feeTypeToInt(type),
+      null,
+      discount
+        ? {
+            gatekeeperNetwork: discount.gatekeeperNetwork,
+            percentage: discount.percentage || 0,
+            amount: new anchor.BN(discount.amount || 0),
+          }
+        : null,
+      {
+        escrow: !!routing.escrow,
+        treasury: routing.treasury || null,
+        splits: routing.splits || [],
+      },
+    ] as const;
+  }
+
+  private async accountExists(address: web3.PublicKey): Promise<boolean> {
+    const info = await this.provider.connection.getAccountInfo(address);
+    return !!info && info.owner.equals(this.program.programId);
+  }
+
+  /**
+   * Set the provider wallet's fee on the gatekeeper network.
+   * The fee takes effect immediately, replacing any change scheduled with scheduleFeeUpdate.
+   * If batchDiscount is true, the fee is charged once per component pass when several derived passes
+   * are issued at once with issueMany, rather than once for each derived pass.
+   */
   async setFee(
     gatekeeperNetwork: web3.PublicKey,
     issueFee: number,
     refreshFee: number = 0,
     percentage: number = 0,
-    type: FeeType = "IssuerOnly"
+    type: FeeType = "IssuerOnly",
+    discount?: FeeDiscount,
+    routing: FeeRouting = {},
+    batchDiscount: boolean = false
   ): Promise<string> {
     const [feeAddress, bump] = await deriveGatekeeperFeeAddress(
       this.provider.wallet.publicKey,
@@ -287,6 +876,12 @@ export class DerivedPassService {
       this.program
     );
 
+    // escrowed fees need a fee vault - create it if it does not exist yet
+    const preInstructions = await this.getFeeVaultPreInstructions(
+      gatekeeperNetwork,
+      routing
+    );
+
     const accounts = {
       fee: feeAddress,
       authority: this.provider.wallet.publicKey,
@@ -294,22 +889,25 @@ export class DerivedPassService {
       systemProgram: anchor.web3.SystemProgram.programId,
     };
 
-    const feeAlreadyExists = await this.provider.connection
-      .getAccountInfo(feeAddress)
-      .then((info) => info && info.owner.equals(this.program.programId));
+    const feeAlreadyExists = await this.accountExists(feeAddress);
 
     const callSetFee = feeAlreadyExists
       ? this.program.methods.updateFee
       : this.program.methods.createFee;
 
     const transactionSignature = await callSetFee(
-      new anchor.BN(issueFee),
-      new anchor.BN(refreshFee),
-      percentage,
-      feeTypeToInt(type),
-      null
+      ...this.toFeeArgs(
+        issueFee,
+        refreshFee,
+        percentage,
+        type,
+        discount,
+        routing
+      ),
+      batchDiscount
     )
       .accounts(accounts)
+      .preInstructions(preInstructions)
       .rpc();
 
     await this.provider.connection.confirmTransaction(transactionSignature);
@@ -317,10 +915,118 @@ export class DerivedPassService {
     return transactionSignature;
   }
 
-  async unsetFee(gatekeeperNetwork: web3.PublicKey): Promise<string> {
-    const [feeAddress, bump] = await deriveGatekeeperFeeAddress(
+  /**
+   * Schedule a change to the provider wallet's fee on the gatekeeper network, taking effect at the effective time
+   * (a unix timestamp). Until then, the current fee is charged.
+   */
+  async scheduleFeeUpdate(
+    gatekeeperNetwork: web3.PublicKey,
+    effectiveTime: number,
+    issueFee: number,
+    refreshFee: number = 0,
+    percentage: number = 0
+  ): Promise<string> {
+    const [feeAddress] = await deriveGatekeeperFeeAddress(
+      this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      this.program
+    );
+
+    const transactionSignature = await this.program.methods
+      .scheduleFeeUpdate(
+        new anchor.BN(issueFee),
+        new anchor.BN(refreshFee),
+        percentage,
+        new anchor.BN(effectiveTime)
+      )
+      .accounts({
+        fee: feeAddress,
+        authority: this.provider.wallet.publicKey,
+        gatekeeperNetwork,
+      })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Apply a scheduled fee change that has taken effect. Can be called by anyone.
+   * If a derived pass is given, the change to the gatekeeper's fee override for the derived pass is applied.
+   */
+  async applyPendingFee(
+    gatekeeper: web3.PublicKey,
+    gatekeeperNetwork: web3.PublicKey,
+    derivedPass?: web3.PublicKey
+  ): Promise<string> {
+    const [feeAddress] = derivedPass
+      ? await deriveFeeOverrideAddress(
+          gatekeeper,
+          gatekeeperNetwork,
+          derivedPass,
+          this.program
+        )
+      : await deriveGatekeeperFeeAddress(
+          gatekeeper,
+          gatekeeperNetwork,
+          this.program
+        );
+
+    const transactionSignature = await this.program.methods
+      .applyPendingFee()
+      .accounts({ fee: feeAddress })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Cancel a scheduled change to the provider wallet's fee that has not yet taken effect.
+   */
+  async cancelPendingFee(gatekeeperNetwork: web3.PublicKey): Promise<string> {
+    const [feeAddress] = await deriveGatekeeperFeeAddress(
+      this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      this.program
+    );
+
+    const transactionSignature = await this.program.methods
+      .cancelPendingFee()
+      .accounts({
+        fee: feeAddress,
+        authority: this.provider.wallet.publicKey,
+        gatekeeperNetwork,
+      })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Set a fee for the use of the provider wallet's passes on the gatekeeper network in a specific derived pass,
+   * overriding the generic fee set with setFee (e.g. to offer a lower rate to a partner).
+   * When several derived passes are issued at once with issueMany, the override applies to its derived pass only.
+   * Overrides are paid directly, so the routing cannot escrow fees.
+   */
+  async setFeeOverride(
+    gatekeeperNetwork: web3.PublicKey,
+    derivedPass: web3.PublicKey,
+    issueFee: number,
+    refreshFee: number = 0,
+    percentage: number = 0,
+    type: FeeType = "IssuerOnly",
+    discount?: FeeDiscount,
+    routing: FeeRouting = {}
+  ): Promise<string> {
+    const [feeAddress] = await deriveFeeOverrideAddress(
       this.provider.wallet.publicKey,
       gatekeeperNetwork,
+      derivedPass,
       this.program
     );
 
@@ -328,10 +1034,26 @@ export class DerivedPassService {
       fee: feeAddress,
       authority: this.provider.wallet.publicKey,
       gatekeeperNetwork,
+      derivedPass,
+      systemProgram: anchor.web3.SystemProgram.programId,
     };
 
-    const transactionSignature = await this.program.methods
-      .removeFee()
+    const overrideAlreadyExists = await this.accountExists(feeAddress);
+
+    const callSetFeeOverride = overrideAlreadyExists
+      ? this.program.methods.updateFeeOverride
+      : this.program.methods.createFeeOverride;
+
+    const transactionSignature = await callSetFeeOverride(
+      ...this.toFeeArgs(
+        issueFee,
+        refreshFee,
+        percentage,
+        type,
+        discount,
+        routing
+      )
+    )
       .accounts(accounts)
       .rpc();
 
@@ -339,4 +1061,543 @@ export class DerivedPassService {
 
     return transactionSignature;
   }
+
+  /**
+   * Schedule a change to the provider wallet's fee override for a derived pass, taking effect at the effective time
+   * (a unix timestamp). Until then, the current fee override is charged.
+   */
+  async scheduleFeeOverrideUpdate(
+    gatekeeperNetwork: web3.PublicKey,
+    derivedPass: web3.PublicKey,
+    effectiveTime: number,
+    issueFee: number,
+    refreshFee: number = 0,
+    percentage: number = 0
+  ): Promise<string> {
+    const [feeAddress] = await deriveFeeOverrideAddress(
+      this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      derivedPass,
+      this.program
+    );
+
+    const transactionSignature = await this.program.methods
+      .scheduleFeeOverrideUpdate(
+        new anchor.BN(issueFee),
+        new anchor.BN(refreshFee),
+        percentage,
+        new anchor.BN(effectiveTime)
+      )
+      .accounts({
+        fee: feeAddress,
+        authority: this.provider.wallet.publicKey,
+        gatekeeperNetwork,
+        derivedPass,
+      })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Cancel a scheduled change to the provider wallet's fee override for a derived pass
+   * that has not yet taken effect.
+   */
+  async cancelPendingFeeOverride(
+    gatekeeperNetwork: web3.PublicKey,
+    derivedPass: web3.PublicKey
+  ): Promise<string> {
+    const [feeAddress] = await deriveFeeOverrideAddress(
+      this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      derivedPass,
+      this.program
+    );
+
+    const transactionSignature = await this.program.methods
+      .cancelPendingFeeOverride()
+      .accounts({
+        fee: feeAddress,
+        authority: this.provider.wallet.publicKey,
+        gatekeeperNetwork,
+        derivedPass,
+      })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Set a default fee for all gatekeepers on the gatekeeper network, applied to the passes of any gatekeeper
+   * that has not set a fee with setFee. The provider wallet must be the gatekeeper network.
+   * Network fees are always paid to the issuing gatekeeper, so the routing cannot have a treasury or splits.
+   * Escrowed network fees are paid into each gatekeeper's fee vault, which the gatekeeper must create.
+   * The batch discount applies as for setFee.
+   */
+  async setNetworkFee(
+    issueFee: number,
+    refreshFee: number = 0,
+    percentage: number = 0,
+    type: FeeType = "IssuerOnly",
+    discount?: FeeDiscount,
+    routing: FeeRouting = {},
+    batchDiscount: boolean = false
+  ): Promise<string> {
+    const gatekeeperNetwork = this.provider.wallet.publicKey;
+    const [feeAddress] = await deriveNetworkFeeAddress(
+      gatekeeperNetwork,
+      this.program
+    );
+
+    const accounts = {
+      fee: feeAddress,
+      gatekeeperNetwork,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+
+    const networkFeeAlreadyExists = await this.accountExists(feeAddress);
+
+    const callSetNetworkFee = networkFeeAlreadyExists
+      ? this.program.methods.updateNetworkFee
+      : this.program.methods.createNetworkFee;
+
+    const transactionSignature = await callSetNetworkFee(
+      ...this.toFeeArgs(
+        issueFee,
+        refreshFee,
+        percentage,
+        type,
+        discount,
+        routing
+      ),
+      batchDiscount
+    )
+      .accounts(accounts)
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Schedule a change to the default fee of the gatekeeper network, taking effect at the effective time
+   * (a unix timestamp). The provider wallet must be the gatekeeper network.
+   * Until then, the current network fee is charged.
+   */
+  async scheduleNetworkFeeUpdate(
+    effectiveTime: number,
+    issueFee: number,
+    refreshFee: number = 0,
+    percentage: number = 0
+  ): Promise<string> {
+    const gatekeeperNetwork = this.provider.wallet.publicKey;
+    const [feeAddress] = await deriveNetworkFeeAddress(
+      gatekeeperNetwork,
+      this.program
+    );
+
+    const transactionSignature = await this.program.methods
+      .scheduleNetworkFeeUpdate(
+        new anchor.BN(issueFee),
+        new anchor.BN(refreshFee),
+        percentage,
+        new anchor.BN(effectiveTime)
+      )
+      .accounts({ fee: feeAddress, gatekeeperNetwork })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Apply a scheduled change to the default fee of the gatekeeper network that has taken effect.
+   * Can be called by anyone.
+   */
+  async applyPendingNetworkFee(
+    gatekeeperNetwork: web3.PublicKey
+  ): Promise<string> {
+    const [feeAddress] = await deriveNetworkFeeAddress(
+      gatekeeperNetwork,
+      this.program
+    );
+
+    const transactionSignature = await this.program.methods
+      .applyPendingFee()
+      .accounts({ fee: feeAddress })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Cancel a scheduled change to the default fee of the gatekeeper network that has not yet taken effect.
+   * The provider wallet must be the gatekeeper network.
+   */
+  async cancelPendingNetworkFee(): Promise<string> {
+    const gatekeeperNetwork = this.provider.wallet.publicKey;
+    const [feeAddress] = await deriveNetworkFeeAddress(
+      gatekeeperNetwork,
+      this.program
+    );
+
+    const transactionSignature = await this.program.methods
+      .cancelPendingNetworkFee()
+      .accounts({ fee: feeAddress, gatekeeperNetwork })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  async unsetNetworkFee(): Promise<string> {
+    const gatekeeperNetwork = this.provider.wallet.publicKey;
+    const [feeAddress] = await deriveNetworkFeeAddress(
+      gatekeeperNetwork,
+      this.program
+    );
+
+    const transactionSignature = await this.program.methods
+      .removeNetworkFee()
+      .accounts({ fee: feeAddress, gatekeeperNetwork })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  async unsetFeeOverride(
+    gatekeeperNetwork: web3.PublicKey,
+    derivedPass: web3.PublicKey
+  ): Promise<string> {
+    const [feeAddress] = await deriveFeeOverrideAddress(
+      this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      derivedPass,
+      this.program
+    );
+
+    const accounts = {
+      fee: feeAddress,
+      authority: this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      derivedPass,
+    };
+
+    const transactionSignature = await this.program.methods
+      .removeFeeOverride()
+      .accounts(accounts)
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  async unsetFee(gatekeeperNetwork: web3.PublicKey): Promise<string> {
+    const [feeAddress, bump] = await deriveGatekeeperFeeAddress(
+      this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      this.program
+    );
+
+    const accounts = {
+      fee: feeAddress,
+      authority: this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+    };
+
+    const transactionSignature = await this.program.methods
+      .removeFee()
+      .accounts(accounts)
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Withdraw the fees accrued in the provider wallet's fee vault for the gatekeeper network,
+   * to the fee's treasury or split recipients, if it has them, or otherwise the provider wallet.
+   */
+  async claimFees(gatekeeperNetwork: web3.PublicKey): Promise<string> {
+    const [feeVault] = await deriveFeeVaultAddress(
+      this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      this.program
+    );
+    const [feeAddress] = await deriveGatekeeperFeeAddress(
+      this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      this.program
+    );
+    const fee = await fetchFeeNullable(this.program, feeAddress);
+    const [firstSplitRecipient, ...otherSplitRecipients] = (
+      fee?.routing.splits || []
+    ).map((split) => split.recipient);
+
+    const accounts = {
+      feeVault,
+      authority: this.provider.wallet.publicKey,
+      gatekeeperNetwork,
+      fee: feeAddress,
+      destination:
+        fee?.routing.treasury ||
+        firstSplitRecipient ||
+        this.provider.wallet.publicKey,
+    };
+
+    const transactionSignature = await this.program.methods
+      .claimFees()
+      .accounts(accounts)
+      .remainingAccounts(otherSplitRecipients.map(toAccountMeta(false, true)))
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Link the provider wallet to a source wallet, so that component passes held by the source wallet
+   * can be used to issue derived passes to the provider wallet.
+   * Both wallets must sign the transaction.
+   * @param sourceWallet The wallet holding the component passes
+   */
+  async linkWallet(sourceWallet: Wallet): Promise<string> {
+    const linkedWallet = this.provider.wallet.publicKey;
+    const [walletLink] = await deriveWalletLinkAddress(
+      sourceWallet.publicKey,
+      linkedWallet,
+      this.program
+    );
+
+    const accounts = {
+      walletLink,
+      sourceWallet: sourceWallet.publicKey,
+      linkedWallet,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+
+    const transaction = await this.program.methods
+      .linkWallet()
+      .accounts(accounts)
+      .transaction();
+    transaction.feePayer = linkedWallet;
+    transaction.recentBlockhash = (
+      await this.provider.connection.getLatestBlockhash()
+    ).blockhash;
+
+    const signedBySource = await sourceWallet.signTransaction(transaction);
+    const signed = await this.provider.wallet.signTransaction(signedBySource);
+
+    const transactionSignature =
+      await this.provider.connection.sendRawTransaction(signed.serialize());
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Remove the link between two wallets. Can be called by either wallet.
+   */
+  async unlinkWallet(
+    sourceWallet: web3.PublicKey,
+    linkedWallet: web3.PublicKey
+  ): Promise<string> {
+    const [walletLink] = await deriveWalletLinkAddress(
+      sourceWallet,
+      linkedWallet,
+      this.program
+    );
+
+    const accounts = {
+      walletLink,
+      authority: this.provider.wallet.publicKey,
+    };
+
+    const transactionSignature = await this.program.methods
+      .unlinkWallet()
+      .accounts(accounts)
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Get the accounts for changing the state of a derived pass held by a recipient.
+   * The provider wallet must be the derived pass authority.
+   */
+  private async getPassStateAccounts(
+    gatewayToken: web3.PublicKey,
+    derivedPass: web3.PublicKey
+  ) {
+    const authority = this.provider.wallet.publicKey;
+    const [derivedGatekeeper] = await deriveGatekeeper(authority, this.program);
+    const derivedGatekeeperAccount = await deriveGatekeeperAccount(
+      derivedGatekeeper,
+      derivedPass
+    );
+
+    return {
+      derivedPass,
+      authority,
+      gatewayToken,
+      derivedGatekeeper,
+      derivedGatekeeperAccount,
+      gatewayProgram: GATEWAY_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+  }
+
+  /**
+   * Freeze a derived pass held by a recipient, until it is unfrozen with unfreezePass.
+   * The provider wallet must be the derived pass authority.
+   */
+  async freezePass(
+    gatewayToken: web3.PublicKey,
+    derivedPass: web3.PublicKey
+  ): Promise<string> {
+    const transactionSignature = await this.program.methods
+      .freezePass()
+      .accounts(await this.getPassStateAccounts(gatewayToken, derivedPass))
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Unfreeze a derived pass frozen with freezePass.
+   * The provider wallet must be the derived pass authority.
+   */
+  async unfreezePass(
+    gatewayToken: web3.PublicKey,
+    derivedPass: web3.PublicKey
+  ): Promise<string> {
+    const transactionSignature = await this.program.methods
+      .unfreezePass()
+      .accounts(await this.getPassStateAccounts(gatewayToken, derivedPass))
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Permanently revoke a derived pass held by a recipient. The pass cannot be issued to the same wallet again.
+   * The provider wallet must be the derived pass authority.
+   * @param reason A reason code (0-255) chosen by the derived pass authority, emitted in the PassRevoked event
+   */
+  async revokePass(
+    gatewayToken: web3.PublicKey,
+    derivedPass: web3.PublicKey,
+    reason: number
+  ): Promise<string> {
+    const transactionSignature = await this.program.methods
+      .revokePass(reason)
+      .accounts(await this.getPassStateAccounts(gatewayToken, derivedPass))
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Get the usage statistics of a derived pass
+   * @return the statistics, or null if the derived pass has no statistics account (see createDerivedPassStats)
+   */
+  async getStats(derivedPass: web3.PublicKey) {
+    const [stats] = await deriveStatsAddress(derivedPass, this.program);
+    return this.program.account.derivedPassStats.fetchNullable(stats);
+  }
+
+  /**
+   * Get the state of a derived pass held by the provider wallet, used to enforce the refresh limits of the derived pass
+   * @return the state, or null if the derived pass has no refresh limits or has not been issued to the provider wallet
+   */
+  async getRecipientState(derivedPass: web3.PublicKey) {
+    const [recipientState] = await deriveRecipientStateAddress(
+      derivedPass,
+      this.provider.wallet.publicKey,
+      this.program
+    );
+    return this.program.account.recipientState.fetchNullable(recipientState);
+  }
+
+  /**
+   * Create the statistics account of a derived pass created by an older version of the program.
+   * The provider wallet pays the rent.
+   */
+  async createDerivedPassStats(derivedPass: web3.PublicKey): Promise<string> {
+    const [stats] = await deriveStatsAddress(derivedPass, this.program);
+    const transactionSignature = await this.program.methods
+      .createDerivedPassStats()
+      .accounts({
+        derivedPass,
+        stats,
+        payer: this.provider.wallet.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Upgrade a derived pass created by an older version of the program to the current layout.
+   * The provider wallet pays any extra rent.
+   */
+  async migrateDerivedPass(derivedPass: web3.PublicKey): Promise<string> {
+    const transactionSignature = await this.program.methods
+      .migrateDerivedPass()
+      .accounts({
+        derivedPass,
+        payer: this.provider.wallet.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
+
+  /**
+   * Upgrade a fee account (a fee, fee override or network fee) created by an older version of the program
+   * to the current layout. The provider wallet pays any extra rent.
+   */
+  async migrateFee(fee: web3.PublicKey): Promise<string> {
+    const transactionSignature = await this.program.methods
+      .migrateFee()
+      .accounts({
+        fee,
+        payer: this.provider.wallet.publicKey,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      })
+      .rpc();
+
+    await this.provider.connection.confirmTransaction(transactionSignature);
+
+    return transactionSignature;
+  }
 }
@@ -1 +1,9 @@
 export { DerivedPassService } from "./DerivedPassService";
+export { resolveDerivedPassGraph, DerivedPassGraph } from "./lib/graph";
+export {
+  fetchDerivedPass,
+  fetchDerivedPassNullable,
+  fetchFeeNullable,
+  DerivedPassAccount,
+  FeeAccount,
+} from "./lib/migration";
@@ -0,0 +1,59 @@
+import { Program, web3 } from "@project-serum/anchor";
+import { GatewayDerive } from "../../target/types/gateway_derive";
+import { fetchDerivedPassNullable } from "./migration";
+
+export type DerivedPassGraph = {
+  // the number of levels of derived passes, including this one.
+  // A derived pass made up of standard gatekeeper networks only has depth 1.
+  depth: number;
+  // if the graph contains a cycle, the path of derived passes forming it,
+  // starting and ending with the same derived pass
+  cycle: web3.PublicKey[] | null;
+};
+
+/**
+ * Walk the constituent passes of a derived pass, following any that are themselves derived passes,
+ * to detect cycles and report the depth of the graph.
+ * Constituent passes that are not DerivedPass accounts are treated as standard gatekeeper networks.
+ */
+export const resolveDerivedPassGraph = async (
+  program: Program<GatewayDerive>,
+  derivedPass: web3.PublicKey
+): Promise<DerivedPassGraph> => {
+  // cache fetched accounts, as a pass type may appear in more than one branch
+  const sourceGknCache = new Map<string, web3.PublicKey[] | null>();
+  const fetchSourceGkns = async (
+    passType: web3.PublicKey
+  ): Promise<web3.PublicKey[] | null> => {
+    const cacheKey = passType.toBase58();
+    if (!sourceGknCache.has(cacheKey)) {
+      const account = await fetchDerivedPassNullable(program, passType);
+      sourceGknCache.set(cacheKey, account ? account.sourceGkns : null);
+    }
+    return sourceGknCache.get(cacheKey) as web3.PublicKey[] | null;
+  };
+
+  const visit = async (
+    passType: web3.PublicKey,
+    path: web3.PublicKey[]
+  ): Promise<DerivedPassGraph> => {
+    const cycleStart = path.findIndex((p) => p.equals(passType));
+    if (cycleStart >= 0)
+      return { depth: 0, cycle: [...path.slice(cycleStart), passType] };
+
+    const sourceGkns = await fetchSourceGkns(passType);
+    // a standard gatekeeper network
+    if (!sourceGkns) return { depth: 0, cycle: null };
+
+    let depth = 0;
+    for (const sourceGkn of sourceGkns) {
+      const result = await visit(sourceGkn, [...path, passType]);
+      if (result.cycle) return result;
+      depth = Math.max(depth, result.depth);
+    }
+
+    return { depth: depth + 1, cycle: null };
+  };
+
+  return visit(derivedPass, []);
+};
@@ -0,0 +1,197 @@
+import {
+  BN,
+  BorshAccountsCoder,
+  BorshCoder,
+  IdlAccounts,
+  Program,
+  web3,
+} from "@project-serum/anchor";
+import { GatewayDerive } from "../../target/types/gateway_derive";
+
+export type DerivedPassAccount = IdlAccounts<GatewayDerive>["derivedPass"];
+export type FeeAccount = IdlAccounts<GatewayDerive>["fee"];
+
+// should match DERIVED_PASS_VERSION and FEE_VERSION in migration.rs
+export const DERIVED_PASS_VERSION = 1;
+export const FEE_VERSION = 1;
+
+const DISCRIMINATOR_SIZE = 8;
+
+// The layouts of accounts created before account versioning (version 0).
+// These should match the V0 structs in migration.rs
+const v0Coder = new BorshCoder({
+  version: "0.0.0",
+  name: "gateway_derive_v0",
+  instructions: [],
+  types: [
+    {
+      name: "DerivedPassPropertiesV0",
+      type: {
+        kind: "struct",
+        fields: [
+          { name: "expireDuration", type: { option: "i64" } },
+          { name: "expireOnUse", type: "bool" },
+          { name: "refreshDisabled", type: "bool" },
+        ],
+      },
+    },
+    {
+      name: "DerivedPassV0",
+      type: {
+        kind: "struct",
+        fields: [
+          { name: "version", type: "u8" },
+          { name: "authority", type: "publicKey" },
+          { name: "gatekeeperBump", type: "u8" },
+          { name: "sourceGkns", type: { vec: "publicKey" } },
+          {
+            name: "properties",
+            type: { defined: "DerivedPassPropertiesV0" },
+          },
+        ],
+      },
+    },
+    {
+      name: "FeeType",
+      type: { kind: "enum", variants: [{ name: "IssuerOnly" }] },
+    },
+    {
+      name: "FeeV0",
+      type: {
+        kind: "struct",
+        fields: [
+          { name: "version", type: "u8" },
+          { name: "feeType", type: { defined: "FeeType" } },
+          { name: "percentage", type: "u8" },
+          { name: "issueAmount", type: "u64" },
+          { name: "refreshAmount", type: "u64" },
+          { name: "mint", type: { option: "publicKey" } },
+        ],
+      },
+    },
+  ],
+});
+
+/**
+ * Fetch the data of a program account of the given type,
+ * or null if the account does not exist or is of a different type.
+ */
+const fetchAccountData = async (
+  program: Program<GatewayDerive>,
+  address: web3.PublicKey,
+  accountName: string
+): Promise<Buffer | null> => {
+  const accountInfo = await program.provider.connection.getAccountInfo(
+    address
+  );
+  if (!accountInfo || !accountInfo.owner.equals(program.programId))
+    return null;
+
+  const discriminator = BorshAccountsCoder.accountDiscriminator(accountName);
+  if (!discriminator.equals(accountInfo.data.slice(0, DISCRIMINATOR_SIZE)))
+    return null;
+
+  return accountInfo.data;
+};
+
+/**
+ * Fetch a derived pass of any supported version, converting it to the current layout (see load_derived_pass).
+ * The version is left unchanged, so that a derived pass that needs migrating can be told.
+ * Returns null if the account is not a derived pass, e.g. if it is a standard gatekeeper network.
+ */
+export const fetchDerivedPassNullable = async (
+  program: Program<GatewayDerive>,
+  address: web3.PublicKey
+): Promise<DerivedPassAccount | null> => {
+  const data = await fetchAccountData(program, address, "DerivedPass");
+  if (!data) return null;
+
+  const version = data[DISCRIMINATOR_SIZE];
+  switch (version) {
+    case 0: {
+      const derivedPass = v0Coder.types.decode(
+        "DerivedPassV0",
+        data.slice(DISCRIMINATOR_SIZE)
+      );
+      // the fields added since version 0 take their defaults
+      return {
+        ...derivedPass,
+        counters: {
+          issued: new BN(0),
+          windowStart: new BN(0),
+          windowIssued: 0,
+        },
+        properties: {
+          ...derivedPass.properties,
+          allowLinkedWallets: false,
+          feeCaps: null,
+          maxSupply: null,
+          maxIssuesPerWindow: null,
+          minRefreshInterval: null,
+          maxRefreshes: null,
+          requireComponentExpiry: false,
+          minComponentValidity: null,
+          issueNotBefore: null,
+          issueNotAfter: null,
+          issueWindowAppliesToRefresh: false,
+          expireAt: null,
+          refreshWindow: null,
+          refreshExtension: { fromNow: {} },
+        },
+      } as DerivedPassAccount;
+    }
+    case DERIVED_PASS_VERSION:
+      return program.coder.accounts.decode<DerivedPassAccount>(
+        "DerivedPass",
+        data
+      );
+    default:
+      throw new Error(`Unsupported derived pass version ${version}`);
+  }
+};
+
+/**
+ * Fetch a derived pass of any supported version, converting it to the current layout (see load_derived_pass).
+ * Throws if the account is not a derived pass.
+ */
+export const fetchDerivedPass = async (
+  program: Program<GatewayDerive>,
+  address: web3.PublicKey
+): Promise<DerivedPassAccount> => {
+  const derivedPass = await fetchDerivedPassNullable(program, address);
+  if (!derivedPass)
+    throw new Error(`${address.toBase58()} is not a derived pass`);
+  return derivedPass;
+};
+
+/**
+ * Fetch a fee, fee override or network fee of any supported version,
+ * converting it to the current layout (see load_fee).
+ * Returns null if the account does not exist.
+ */
+export const fetchFeeNullable = async (
+  program: Program<GatewayDerive>,
+  address: web3.PublicKey
+): Promise<FeeAccount | null> => {
+  const data = await fetchAccountData(program, address, "Fee");
+  if (!data) return null;
+
+  const version = data[DISCRIMINATOR_SIZE];
+  switch (version) {
+    case 0: {
+      const fee = v0Coder.types.decode("FeeV0", data.slice(DISCRIMINATOR_SIZE));
+      // the fields added since version 0 take their defaults
+      return {
+        ...fee,
+        discount: null,
+        routing: { escrow: false, treasury: null, splits: [] },
+        pending: null,
+        batchDiscount: false,
+      } as FeeAccount;
+    }
+    case FEE_VERSION:
+      return program.coder.accounts.decode<FeeAccount>("Fee", data);
+    default:
+      throw new Error(`Unsupported fee version ${version}`);
+  }
+};
@@ -1,17 +1,25 @@
-import { Program, Provider, web3 } from "@project-serum/anchor";
+import { BN, Program, Provider, web3 } from "@project-serum/anchor";
 import {
   findGatewayToken,
   GatewayToken,
   getGatekeeperAccountAddress,
 } from "@identity.com/solana-gateway-ts";
 import { GatewayDerive } from "../../target/types/gateway_derive";
+import { fetchDerivedPass } from "./migration";
 
 const GATEKEEPER_SEED = "gateway_derive_gk_seed";
 const FEE_SEED = "gateway_derive_fee_seed";
+const WALLET_LINK_SEED = "gateway_derive_link_seed";
+const VOUCHER_SEED = "gateway_derive_voucher_seed";
+const FEE_VAULT_SEED = "gateway_derive_vault_seed";
+const FEE_OVERRIDE_SEED = "gateway_derive_fee_override_seed";
+const NETWORK_FEE_SEED = "gateway_derive_network_fee_seed";
+const STATS_SEED = "gateway_derive_stats_seed";
+const RECIPIENT_STATE_SEED = "gateway_derive_recipient_seed";
+export const VOUCHER_MESSAGE_PREFIX = "gateway_derive_voucher";
 const DERIVED_PASS_PROGRAM = new web3.PublicKey(
   "derd45BqhDfR4w5R45ohkTuimsRegTP6KnXkmT7x9HH"
 );
-const DERIVED_PASS_PROPERTIES_SIZE = 8 + 1 + 1;
 
 export const deriveGatekeeper = async (
   authority: web3.PublicKey,
@@ -42,6 +50,96 @@ export const deriveGatekeeperFeeAddress = async (
     program.programId
   );
 
+export const deriveFeeOverrideAddress = async (
+  gatekeeper: web3.PublicKey,
+  gatekeeperNetwork: web3.PublicKey,
+  derivedPass: web3.PublicKey,
+  program: Program<GatewayDerive>
+): Promise<[web3.PublicKey, number]> =>
+  web3.PublicKey.findProgramAddress(
+    [
+      Buffer.from(FEE_OVERRIDE_SEED),
+      gatekeeper.toBuffer(),
+      gatekeeperNetwork.toBuffer(),
+      derivedPass.toBuffer(),
+    ],
+    program.programId
+  );
+
+export const deriveNetworkFeeAddress = async (
+  gatekeeperNetwork: web3.PublicKey,
+  program: Program<GatewayDerive>
+): Promise<[web3.PublicKey, number]> =>
+  web3.PublicKey.findProgramAddress(
+    [Buffer.from(NETWORK_FEE_SEED), gatekeeperNetwork.toBuffer()],
+    program.programId
+  );
+
+export const deriveStatsAddress = async (
+  derivedPass: web3.PublicKey,
+  program: Program<GatewayDerive>
+): Promise<[web3.PublicKey, number]> =>
+  web3.PublicKey.findProgramAddress(
+    [Buffer.from(STATS_SEED), derivedPass.toBuffer()],
+    program.programId
+  );
+
+export const deriveRecipientStateAddress = async (
+  derivedPass: web3.PublicKey,
+  recipient: web3.PublicKey,
+  program: Program<GatewayDerive>
+): Promise<[web3.PublicKey, number]> =>
+  web3.PublicKey.findProgramAddress(
+    [
+      Buffer.from(RECIPIENT_STATE_SEED),
+      derivedPass.toBuffer(),
+      recipient.toBuffer(),
+    ],
+    program.programId
+  );
+
+export const deriveFeeVaultAddress = async (
+  gatekeeper: web3.PublicKey,
+  gatekeeperNetwork: web3.PublicKey,
+  program: Program<GatewayDerive>
+): Promise<[web3.PublicKey, number]> =>
+  web3.PublicKey.findProgramAddress(
+    [
+      Buffer.from(FEE_VAULT_SEED),
+      gatekeeper.toBuffer(),
+      gatekeeperNetwork.toBuffer(),
+    ],
+    program.programId
+  );
+
+export const deriveWalletLinkAddress = async (
+  sourceWallet: web3.PublicKey,
+  linkedWallet: web3.PublicKey,
+  program: Program<GatewayDerive>
+): Promise<[web3.PublicKey, number]> =>
+  web3.PublicKey.findProgramAddress(
+    [
+      Buffer.from(WALLET_LINK_SEED),
+      sourceWallet.toBuffer(),
+      linkedWallet.toBuffer(),
+    ],
+    program.programId
+  );
+
+export const deriveVoucherAddress = async (
+  signer: web3.PublicKey,
+  id: BN,
+  program: Program<GatewayDerive>
+): Promise<[web3.PublicKey, number]> =>
+  web3.PublicKey.findProgramAddress(
+    [
+      Buffer.from(VOUCHER_SEED),
+      signer.toBuffer(),
+      id.toArrayLike(Buffer, "le", 8),
+    ],
+    program.programId
+  );
+
 export const fetchProgram = async (
   provider: Provider
 ): Promise<Program<GatewayDerive>> => {
@@ -77,21 +175,33 @@ export const toAccountMeta =
  */
 export const toSimpleAccountMeta = toAccountMeta(false, false);
 
-export const calculateDerivedPassSize = (sourceGkns: web3.PublicKey[]) =>
-  16 + sourceGkns.length * 32 + 32 + DERIVED_PASS_PROPERTIES_SIZE;
-
+/**
+ * Find the component passes needed to issue the derived pass to the owner.
+ * If a source wallet is linked to the owner, passes held by the source wallet
+ * are used for any pass type that the owner does not hold.
+ */
 export const findComponentPassesForDerivedPass = async (
   program: Program<GatewayDerive>,
   derivedPass: web3.PublicKey,
-  owner: web3.PublicKey
+  owner: web3.PublicKey,
+  sourceWallet?: web3.PublicKey
 ): Promise<GatewayToken[]> => {
-  const derivedPassAccount = await program.account.derivedPass.fetch(
-    derivedPass
-  );
+  const derivedPassAccount = await fetchDerivedPass(program, derivedPass);
   const sourcePassTypes = derivedPassAccount.sourceGkns;
-  const sourcePassPromises = sourcePassTypes.map((sourcePassType) =>
-    findGatewayToken(program.provider.connection, owner, sourcePassType)
-  );
+  const findPass = async (sourcePassType: web3.PublicKey) => {
+    const pass = await findGatewayToken(
+      program.provider.connection,
+      owner,
+      sourcePassType
+    );
+    if (pass || !sourceWallet) return pass;
+    return findGatewayToken(
+      program.provider.connection,
+      sourceWallet,
+      sourcePassType
+    );
+  };
+  const sourcePassPromises = sourcePassTypes.map(findPass);
   const sourcePasses = await Promise.all(sourcePassPromises);
   return sourcePasses.filter(Boolean) as GatewayToken[];
 };
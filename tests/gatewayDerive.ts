@@ -3,17 +3,32 @@ import { AnchorProvider, Program, Wallet, web3 } from "@project-serum/anchor";
 import chai from "chai";
 import chaiAsPromised from "chai-as-promised";
 import sinon from "sinon";
-import { pluck } from "ramda";
+import { readFileSync } from "fs";
+import { chain, pluck } from "ramda";
+import * as nacl from "tweetnacl";
 import { GatekeeperService } from "@identity.com/solana-gatekeeper-lib";
 import {
+  addGatekeeper as addGatekeeperInstruction,
   findGatewayToken,
   GatewayToken,
+  getGatewayTokenAddressForOwnerAndGatekeeperNetwork,
+  PROGRAM_ID as GATEWAY_PROGRAM_ID,
+  State,
 } from "@identity.com/solana-gateway-ts";
 
 import { GatewayDerive } from "../target/types/gateway_derive";
 import { addGatekeeper, fund, sendGatewayTransaction } from "./gatekeeperUtils";
 
-import { DerivedPassService } from "../src/";
+import {
+  DerivedPassService,
+  fetchDerivedPass,
+  fetchFeeNullable,
+  resolveDerivedPassGraph,
+} from "../src/";
+import {
+  Properties,
+  toDerivedPassProperties,
+} from "../src/DerivedPassService";
 import * as util from "../src/lib/util";
 import { deriveGatekeeperFeeAddress } from "../src/lib/util";
 
@@ -93,6 +108,85 @@ describe("gateway-derive", () => {
       );
       expect(derivedPassAccount.sourceGkns).to.deep.equal(sourceGknKeys);
     });
+
+    it("reports the depth of a derived pass made of derived passes", async () => {
+      const [, innerDerivedPass] = await service.derivePass(sourceGknKeys);
+      const [, outerDerivedPass] = await service.derivePass([
+        innerDerivedPass,
+        sourceGknKeys[0],
+      ]);
+
+      const graph = await resolveDerivedPassGraph(program, outerDerivedPass);
+
+      expect(graph.depth).to.equal(2);
+      expect(graph.cycle).to.be.null;
+    });
+
+    it("rejects a derived pass that includes itself as a component pass", async () => {
+      // build the instruction directly, as the derived pass address is not known to derivePass in advance
+      const derivedPass = web3.Keypair.generate();
+      const [derivedGatekeeper, derivedGatekeeperBump] =
+        await util.deriveGatekeeper(authority, program);
+      const derivedGatekeeperAccount = await util.deriveGatekeeperAccount(
+        derivedGatekeeper,
+        derivedPass.publicKey
+      );
+      const [stats] = await util.deriveStatsAddress(
+        derivedPass.publicKey,
+        program
+      );
+
+      const shouldFail = program.methods
+        .initialize(
+          [derivedPass.publicKey, ...sourceGknKeys],
+          0,
+          derivedGatekeeperBump,
+          toDerivedPassProperties({})
+        )
+        .accounts({
+          derivedPass: derivedPass.publicKey,
+          authority,
+          derivedGatekeeper,
+          derivedGatekeeperAccount,
+          stats,
+          gatewayProgram: GATEWAY_PROGRAM_ID,
+          systemProgram: web3.SystemProgram.programId,
+        })
+        .signers([derivedPass])
+        .rpc();
+
+      return expect(shouldFail).to.be.rejectedWith(/CircularDerivedPass/);
+    });
+
+    it("rejects an issue quota without a positive window duration", () => {
+      const shouldFail = service.derivePass(sourceGknKeys, {
+        maxIssuesPerWindow: { windowDuration: 0, maxIssues: 10 },
+      });
+
+      return expect(shouldFail).to.be.rejectedWith(/InvalidIssueQuota/);
+    });
+
+    it("rejects a refresh extended from expiry without a positive maximum horizon", () => {
+      const shouldFail = service.derivePass(sourceGknKeys, {
+        expireDuration: 60,
+        refreshFromExpiry: { maxHorizon: 0 },
+      });
+
+      return expect(shouldFail).to.be.rejectedWith(/InvalidRefreshExtension/);
+    });
+
+    it("does not migrate a derived pass of the current version", async () => {
+      const [, derivedPass] = await service.derivePass(sourceGknKeys);
+
+      const derivedPassAccount = await program.account.derivedPass.fetch(
+        derivedPass
+      );
+      expect(derivedPassAccount.version).to.equal(1);
+
+      const shouldFail = service.migrateDerivedPass(derivedPass);
+
+      return expect(shouldFail).to.be.rejectedWith(/AccountAlreadyMigrated/);
+    });
   });
 
   context("derived pass issuance", () => {
@@ -141,6 +235,292 @@ describe("gateway-derive", () => {
         );
       });
 
+      it("should allow the derived pass authority to freeze and unfreeze a pass", async () => {
+        const authorityService = new DerivedPassService(
+          program,
+          authorityProvider
+        );
+        const [, gatewayToken] = await service.issue(authority, derivedPass);
+        const getState = async () =>
+          (
+            await findGatewayToken(
+              authorityProvider.connection,
+              owner.publicKey,
+              derivedPass
+            )
+          )?.state;
+
+        await authorityService.freezePass(gatewayToken, derivedPass);
+        expect(await getState()).to.equal(State.FROZEN);
+
+        await authorityService.unfreezePass(gatewayToken, derivedPass);
+        expect(await getState()).to.equal(State.ACTIVE);
+      });
+
+      it("should not reissue a pass revoked by the derived pass authority", async () => {
+        const authorityService = new DerivedPassService(
+          program,
+          authorityProvider
+        );
+        const [, gatewayToken] = await service.issue(authority, derivedPass);
+
+        await authorityService.revokePass(gatewayToken, derivedPass, 1);
+
+        const foundToken = await findGatewayToken(
+          authorityProvider.connection,
+          owner.publicKey,
+          derivedPass
+        );
+        expect(foundToken).to.be.null;
+
+        const shouldFail = service.issue(authority, derivedPass);
+        return expect(shouldFail).to.be.rejectedWith(/PassRevoked/);
+      });
+
+      it("should be able to derive several passes at once", async () => {
+        const authorityService = new DerivedPassService(
+          program,
+          authorityProvider
+        );
+        const [, secondDerivedPass] = await authorityService.derivePass([
+          sourceGknKeys[0],
+        ]);
+
+        const [, gatewayTokens] = await service.issueMany([
+          derivedPass,
+          secondDerivedPass,
+        ]);
+
+        const foundTokens = await Promise.all(
+          [derivedPass, secondDerivedPass].map((gkn) =>
+            findGatewayToken(
+              authorityProvider.connection,
+              owner.publicKey,
+              gkn
+            )
+          )
+        );
+        expect(
+          foundTokens.map((token) => token?.publicKey.toBase58())
+        ).to.deep.equal(gatewayTokens.map((token) => token.toBase58()));
+      });
+
+      it("should not issue more than the max supply of the derived pass", async () => {
+        const authorityService = new DerivedPassService(
+          program,
+          authorityProvider
+        );
+        const [, limitedDerivedPass] = await authorityService.derivePass(
+          sourceGknKeys,
+          { maxSupply: 1 }
+        );
+        await service.issue(authority, limitedDerivedPass);
+
+        const derivedPassAccount = await program.account.derivedPass.fetch(
+          limitedDerivedPass
+        );
+        expect(derivedPassAccount.counters.issued.toNumber()).to.equal(1);
+
+        // a second recipient with the component passes
+        const secondOwner = web3.Keypair.generate();
+        await fund(authorityProvider, secondOwner.publicKey);
+        await Promise.all(
+          civicGatekeeperServices.map((gks) =>
+            sendGatewayTransaction(() => gks.issue(secondOwner.publicKey))
+          )
+        );
+        const secondOwnerService = new DerivedPassService(
+          program,
+          new AnchorProvider(
+            authorityProvider.connection,
+            new Wallet(secondOwner),
+            AnchorProvider.defaultOptions()
+          )
+        );
+
+        const shouldFail = secondOwnerService.issue(
+          authority,
+          limitedDerivedPass
+        );
+
+        return expect(shouldFail).to.be.rejectedWith(/MaxSupplyReached/);
+      });
+
+      it("should reject component passes without an expiry time if the derived pass requires one", async () => {
+        const authorityService = new DerivedPassService(
+          program,
+          authorityProvider
+        );
+        const [, strictDerivedPass] = await authorityService.derivePass(
+          sourceGknKeys,
+          { requireComponentExpiry: true }
+        );
+
+        // the component passes are issued without an expiry time
+        const shouldFail = service.issue(authority, strictDerivedPass);
+
+        return expect(shouldFail).to.be.rejectedWith(
+          /ComponentPassNotExpirable/
+        );
+      });
+
+      it("should not issue the derived pass after its issue window has closed", async () => {
+        const authorityService = new DerivedPassService(
+          program,
+          authorityProvider
+        );
+        const now = Math.floor(Date.now() / 1000);
+        const [, campaignDerivedPass] = await authorityService.derivePass(
+          sourceGknKeys,
+          { issueNotBefore: now - 7200, issueNotAfter: now - 3600 }
+        );
+
+        const shouldFail = service.issue(authority, campaignDerivedPass);
+
+        return expect(shouldFail).to.be.rejectedWith(/IssueWindowClosed/);
+      });
+
+      context("with the component pass accounts built directly", () => {
+        // issue the derived pass with the given number of accounts in each component pass group,
+        // appending any extra accounts to the group of the first component pass.
+        // The shared accounts default to the network fee accounts, as the gatekeepers have no fees here
+        const issueWithAccountCounts = async (
+          componentAccountCounts: number[],
+          extraAccounts: web3.PublicKey[] = [],
+          sharedAccounts?: web3.PublicKey[]
+        ) => {
+          const ownerProgram = new Program<GatewayDerive>(
+            program.idl,
+            program.programId,
+            ownerProvider
+          );
+          const componentPasses = await util.findComponentPassesForDerivedPass(
+            program,
+            derivedPass,
+            owner.publicKey
+          );
+          const groups = await Promise.all(
+            componentPasses.map(async (pass) => {
+              const [feeAddress, feeAddressBump] =
+                await deriveGatekeeperFeeAddress(
+                  pass.issuingGatekeeper,
+                  pass.gatekeeperNetwork,
+                  program
+                );
+              return {
+                accounts: [
+                  util.toSimpleAccountMeta(pass.publicKey),
+                  util.toSimpleAccountMeta(feeAddress),
+                  util.toAccountMeta(false, true)(pass.issuingGatekeeper),
+                ],
+                feeAddressBump,
+              };
+            })
+          );
+          groups[0].accounts.push(
+            ...extraAccounts.map(util.toSimpleAccountMeta)
+          );
+          const networkFees = await Promise.all(
+            componentPasses.map(async (pass) => {
+              const [networkFee] = await util.deriveNetworkFeeAddress(
+                pass.gatekeeperNetwork,
+                program
+              );
+              return networkFee;
+            })
+          );
+
+          const [derivedGatekeeper] = await util.deriveGatekeeper(
+            authority,
+            program
+          );
+          const [stats, statsBump] = await util.deriveStatsAddress(
+            derivedPass,
+            program
+          );
+          const [recipientState] = await util.deriveRecipientStateAddress(
+            derivedPass,
+            owner.publicKey,
+            program
+          );
+
+          return ownerProgram.methods
+            .issue(
+              Buffer.from(groups.map((group) => group.feeAddressBump)),
+              Buffer.from(componentAccountCounts),
+              null,
+              statsBump
+            )
+            .accounts({
+              derivedPass,
+              recipient: owner.publicKey,
+              derivedGatekeeper,
+              derivedGatekeeperAccount: await util.deriveGatekeeperAccount(
+                derivedGatekeeper,
+                derivedPass
+              ),
+              gatewayToken:
+                await getGatewayTokenAddressForOwnerAndGatekeeperNetwork(
+                  owner.publicKey,
+                  derivedPass
+                ),
+              stats,
+              recipientState,
+              gatewayProgram: GATEWAY_PROGRAM_ID,
+              systemProgram: web3.SystemProgram.programId,
+            })
+            .remainingAccounts([
+              ...chain((group) => group.accounts, groups),
+              ...(sharedAccounts || networkFees).map(util.toSimpleAccountMeta),
+            ])
+            .rpc();
+        };
+
+        it("should accept component pass account groups of different sizes", async () => {
+          // an unused extra account in the first group only
+          await issueWithAccountCounts([4, 3], [web3.SystemProgram.programId]);
+
+          const foundToken = await findGatewayToken(
+            authorityProvider.connection,
+            owner.publicKey,
+            derivedPass
+          );
+          expect(foundToken).not.to.be.null;
+        });
+
+        it("should reject fewer component account counts than component passes", () => {
+          const shouldFail = issueWithAccountCounts([6]);
+
+          return expect(shouldFail).to.be.rejectedWith(/IncorrectFeeBumpCount/);
+        });
+
+        it("should reject component account counts exceeding the accounts passed", () => {
+          // no shared accounts, so that the last group runs past the end of the accounts
+          const shouldFail = issueWithAccountCounts([3, 4], [], []);
+
+          return expect(shouldFail).to.be.rejectedWith(
+            /IncorrectAccountLayout/
+          );
+        });
+
+        it("should reject a component account group too small for a component pass", () => {
+          const shouldFail = issueWithAccountCounts(
+            [2, 4],
+            [web3.SystemProgram.programId]
+          );
+
+          return expect(shouldFail).to.be.rejectedWith(
+            /IncorrectAccountLayout/
+          );
+        });
+
+        it("should reject an issue without the network fee accounts if the gatekeepers have no fee", () => {
+          const shouldFail = issueWithAccountCounts([3, 3], [], []);
+
+          return expect(shouldFail).to.be.rejectedWith(/MissingNetworkFee/);
+        });
+      });
+
       context("with fees", () => {
         // fees for the two constituent passes
         const fee0 = 100;
@@ -211,6 +591,112 @@ describe("gateway-derive", () => {
           );
         });
 
+        // Warning, this relies on the previous test running first to set the second fee
+        it("should record the issue and the fees paid in the derived pass statistics", async () => {
+          await service.issue(authority, derivedPass);
+
+          const stats = await service.getStats(derivedPass);
+
+          expect(stats?.issued.toNumber()).to.equal(1);
+          expect(stats?.refreshed.toNumber()).to.equal(0);
+          expect(stats?.issueFeesPaid.toNumber()).to.equal(fee0 + fee1);
+          expect(stats?.lastActivity.toNumber()).to.be.at.least(
+            stats?.createdAt.toNumber() || 0
+          );
+        });
+
+        it("should charge the fees in issueMany for each derived pass", async () => {
+          const authorityService = new DerivedPassService(
+            program,
+            authorityProvider
+          );
+          const [, secondDerivedPass] = await authorityService.derivePass(
+            sourceGknKeys
+          );
+
+          const [transactionSignature] = await service.issueMany([
+            derivedPass,
+            secondDerivedPass,
+          ]);
+
+          const feesPaid = await service.getFeesPaid(transactionSignature);
+          expect(
+            feesPaid?.fees.map((fee) => fee.amount.toNumber())
+          ).to.deep.equal([fee0, fee1, fee0, fee1]);
+
+          const stats = await Promise.all(
+            [derivedPass, secondDerivedPass].map((gkn) => service.getStats(gkn))
+          );
+          expect(stats.map((s) => s?.issued.toNumber())).to.deep.equal([1, 1]);
+          expect(stats.map((s) => s?.issueFeesPaid.toNumber())).to.deep.equal([
+            fee0 + fee1,
+            fee0 + fee1,
+          ]);
+        });
+
+        it("should charge a fee with a batch discount once in issueMany", async () => {
+          await civicGatekeeperDerivedPassService.setFee(
+            sourceGkns[0].publicKey,
+            fee0,
+            0,
+            0,
+            "IssuerOnly",
+            undefined,
+            {},
+            true
+          );
+          const authorityService = new DerivedPassService(
+            program,
+            authorityProvider
+          );
+          const [, secondDerivedPass] = await authorityService.derivePass(
+            sourceGknKeys
+          );
+
+          const [transactionSignature] = await service.issueMany([
+            derivedPass,
+            secondDerivedPass,
+          ]);
+
+          // the first fee is only charged with the first derived pass
+          const feesPaid = await service.getFeesPaid(transactionSignature);
+          expect(
+            feesPaid?.fees.map((fee) => fee.amount.toNumber())
+          ).to.deep.equal([fee0, fee1, 0, fee1]);
+
+          // remove the batch discount, so that the following tests are unaffected
+          await civicGatekeeperDerivedPassService.setFee(
+            sourceGkns[0].publicKey,
+            fee0
+          );
+        });
+
+        it("should apply the fee override of each derived pass in issueMany", async () => {
+          const overrideFee = 10;
+          const authorityService = new DerivedPassService(
+            program,
+            authorityProvider
+          );
+          const [, secondDerivedPass] = await authorityService.derivePass(
+            sourceGknKeys
+          );
+          await civicGatekeeperDerivedPassService.setFeeOverride(
+            sourceGkns[0].publicKey,
+            secondDerivedPass,
+            overrideFee
+          );
+
+          const [transactionSignature] = await service.issueMany([
+            derivedPass,
+            secondDerivedPass,
+          ]);
+
+          const feesPaid = await service.getFeesPaid(transactionSignature);
+          expect(
+            feesPaid?.fees.map((fee) => fee.amount.toNumber())
+          ).to.deep.equal([fee0, fee1, overrideFee, fee1]);
+        });
+
         it("should update the fee", async () => {
           // double the second fee
           await civicGatekeeperDerivedPassService.setFee(
@@ -236,110 +722,605 @@ describe("gateway-derive", () => {
           );
         });
 
-        it("should fail if an invalid fee account is passed", () => {
-          sandbox
-            .stub(util, "deriveGatekeeperFeeAddress")
-            .resolves([web3.Keypair.generate().publicKey, 255]);
-
-          const shouldFail = service.issue(authority, derivedPass);
+        // Warning, this relies on the previous tests running first to set the fees
+        it("should report a breakdown of the fees paid", async () => {
+          const [transactionSignature] = await service.issue(
+            authority,
+            derivedPass
+          );
 
-          return expect(shouldFail).to.be.rejectedWith(/InvalidFeeAccount/);
+          const feesPaid = await service.getFeesPaid(transactionSignature);
+
+          expect(
+            feesPaid?.fees.map((fee) => ({
+              gatekeeper: fee.gatekeeper.toBase58(),
+              gatekeeperNetwork: fee.gatekeeperNetwork.toBase58(),
+              amount: fee.amount.toNumber(),
+            }))
+          ).to.deep.equal([
+            {
+              gatekeeper: civicGatekeeper.publicKey.toBase58(),
+              gatekeeperNetwork: sourceGknKeys[0].toBase58(),
+              amount: fee0,
+            },
+            {
+              gatekeeper: civicGatekeeper.publicKey.toBase58(),
+              gatekeeperNetwork: sourceGknKeys[1].toBase58(),
+              amount: 2 * fee1,
+            },
+          ]);
         });
 
-        it("should fail if an incorrect gatekeeper account is passed", () => {
-          // stub toAccountMeta to pass an incorrect gatekeeper account for the gateekeeper
-          sandbox
-            .stub(util, "toAccountMeta")
-            .callsFake((isSigner, isWritable) => (pubkey: web3.PublicKey) => {
-              if (pubkey.equals(civicGatekeeper.publicKey))
-                return {
-                  pubkey: web3.Keypair.generate().publicKey, // dummy gatekeeper
-                  isSigner,
-                  isWritable,
-                };
+        // Warning, this relies on the previous tests running first to set the fees
+        it("should waive the fees using a voucher signed by the gatekeeper", async () => {
+          const voucher = {
+            id: new anchor.BN(1),
+            signer: civicGatekeeper.publicKey,
+            recipient: owner.publicKey,
+            derivedPass,
+            action: { issue: {} },
+            gatekeeper: civicGatekeeper.publicKey,
+            gatekeeperNetwork: null,
+            amount: new anchor.BN("18446744073709551615"), // u64::MAX
+            expireTime: null,
+          };
+          const signature = nacl.sign.detached(
+            service.getVoucherMessage(voucher),
+            civicGatekeeper.secretKey
+          );
 
-              return {
-                pubkey,
-                isSigner,
-                isWritable,
-              };
-            });
+          const [transactionSignature] = await service.issue(
+            authority,
+            derivedPass,
+            undefined,
+            { voucher, signature }
+          );
 
-          const shouldFail = service.issue(authority, derivedPass);
+          const feesPaid = await service.getFeesPaid(transactionSignature);
+          expect(
+            feesPaid?.fees.map((fee) => fee.amount.toNumber())
+          ).to.deep.equal([0, 0]);
 
-          return expect(shouldFail).to.be.rejectedWith(/GatekeeperMismatch/);
+          // the voucher is marked as used
+          const [voucherAddress] = await util.deriveVoucherAddress(
+            civicGatekeeper.publicKey,
+            voucher.id,
+            program
+          );
+          const voucherAccount =
+            await authorityProvider.connection.getAccountInfo(voucherAddress);
+          expect(voucherAccount?.owner.toBase58()).to.equal(
+            program.programId.toBase58()
+          );
         });
 
-        it("should fail to remove the fee if not the owner", async () => {
-          // create a different gatekeeper that will attempt to remove the fee
-          const differentGatekeeper = web3.Keypair.generate();
-          const differentGatekeeperProvider = new AnchorProvider(
-            authorityProvider.connection,
-            new Wallet(differentGatekeeper),
-            AnchorProvider.defaultOptions()
-          );
-          const differentGatekeeperDerivedPassService = new DerivedPassService(
-            program,
-            differentGatekeeperProvider
+        it("should not waive the fees of a gatekeeper using a voucher signed by the derived pass authority", () => {
+          const voucher = {
+            id: new anchor.BN(2),
+            signer: authority,
+            recipient: owner.publicKey,
+            derivedPass,
+            action: { issue: {} },
+            gatekeeper: civicGatekeeper.publicKey,
+            gatekeeperNetwork: null,
+            amount: new anchor.BN("18446744073709551615"), // u64::MAX
+            expireTime: null,
+          };
+          const signature = nacl.sign.detached(
+            service.getVoucherMessage(voucher),
+            (authorityProvider.wallet as Wallet).payer.secretKey
           );
 
-          // stub the client to pass the civic gatekeeper fee address
-          const deriveResult = await deriveGatekeeperFeeAddress(
+          const shouldFail = service.issue(authority, derivedPass, undefined, {
+            voucher,
+            signature,
+          });
+
+          return expect(shouldFail).to.be.rejectedWith(/InvalidVoucher/);
+        });
+
+        // Warning, this relies on the previous tests running first to set the fees
+        it("should escrow the fees in a fee vault until claimed", async () => {
+          await civicGatekeeperDerivedPassService.setFee(
+            sourceGkns[0].publicKey,
+            fee0,
+            0,
+            0,
+            "IssuerOnly",
+            undefined,
+            { escrow: true }
+          );
+          const [feeVault] = await util.deriveFeeVaultAddress(
             civicGatekeeper.publicKey,
-            sourceGkns[1].publicKey,
+            sourceGkns[0].publicKey,
             program
           );
-          sandbox
-            .stub(util, "deriveGatekeeperFeeAddress")
-            .resolves(deriveResult);
+          const getBalances = () =>
+            Promise.all([
+              authorityProvider.connection.getBalance(
+                civicGatekeeper.publicKey
+              ),
+              authorityProvider.connection.getBalance(feeVault),
+            ]);
 
-          await fund(authorityProvider, differentGatekeeper.publicKey);
+          const [previousGatekeeperBalance, previousVaultBalance] =
+            await getBalances();
 
-          const shouldFail = differentGatekeeperDerivedPassService.unsetFee(
-            sourceGkns[1].publicKey
-          );
+          await service.issue(authority, derivedPass);
 
-          // The derived fee account address will not match the passed-in seeds
-          return expect(shouldFail).to.be.rejectedWith(/ConstraintSeeds/);
-        });
+          // only the fee for the second component pass is paid directly
+          const [newGatekeeperBalance, newVaultBalance] = await getBalances();
+          expect(newGatekeeperBalance - previousGatekeeperBalance).to.equal(
+            2 * fee1
+          );
+          expect(newVaultBalance - previousVaultBalance).to.equal(fee0);
 
-        it("should remove the fee", async () => {
-          await civicGatekeeperDerivedPassService.unsetFee(
-            sourceGkns[1].publicKey
+          await civicGatekeeperDerivedPassService.claimFees(
+            sourceGkns[0].publicKey
           );
 
-          await new Promise((resolve) => setTimeout(resolve, 5000));
+          const [, claimedVaultBalance] = await getBalances();
+          expect(newVaultBalance - claimedVaultBalance).to.equal(fee0);
 
-          const [feeAddress] = await deriveGatekeeperFeeAddress(
-            civicGatekeeper.publicKey,
-            sourceGkns[1].publicKey,
-            program
+          // restore direct payment for the following tests
+          await civicGatekeeperDerivedPassService.setFee(
+            sourceGkns[0].publicKey,
+            fee0
           );
+        });
 
-          const account = await authorityProvider.connection.getAccountInfo(
-            feeAddress
+        // Warning, this relies on the previous tests running first to set the fees
+        it("should pay the fees to the gatekeeper's treasury", async () => {
+          const treasury = web3.Keypair.generate().publicKey;
+          await fund(authorityProvider, treasury);
+          await civicGatekeeperDerivedPassService.setFee(
+            sourceGkns[0].publicKey,
+            fee0,
+            0,
+            0,
+            "IssuerOnly",
+            undefined,
+            { treasury }
           );
 
-          expect(account).to.be.null;
-
-          const previousGatekeeperBalance =
-            await authorityProvider.connection.getBalance(
-              civicGatekeeper.publicKey
-            );
+          const previousTreasuryBalance =
+            await authorityProvider.connection.getBalance(treasury);
 
           await service.issue(authority, derivedPass);
 
-          const newGatekeeperBalance =
-            await authorityProvider.connection.getBalance(
-              civicGatekeeper.publicKey
-            );
+          const newTreasuryBalance =
+            await authorityProvider.connection.getBalance(treasury);
+          expect(newTreasuryBalance - previousTreasuryBalance).to.equal(fee0);
 
-          // no fee1 incurred this time.
-          const expectedTotalFee = fee0;
-          expect(newGatekeeperBalance - previousGatekeeperBalance).to.equal(
+          // restore payment to the gatekeeper for the following tests
+          await civicGatekeeperDerivedPassService.setFee(
+            sourceGkns[0].publicKey,
+            fee0
+          );
+        });
+
+        // Warning, this relies on the previous tests running first to set the fees
+        it("should split the fees between the gatekeeper and a partner", async () => {
+          const partner = web3.Keypair.generate().publicKey;
+          await fund(authorityProvider, partner);
+          await civicGatekeeperDerivedPassService.setFee(
+            sourceGkns[0].publicKey,
+            fee0,
+            0,
+            0,
+            "IssuerOnly",
+            undefined,
+            {
+              splits: [
+                { recipient: civicGatekeeper.publicKey, basisPoints: 7_000 },
+                { recipient: partner, basisPoints: 3_000 },
+              ],
+            }
+          );
+          const getBalances = () =>
+            Promise.all([
+              authorityProvider.connection.getBalance(
+                civicGatekeeper.publicKey
+              ),
+              authorityProvider.connection.getBalance(partner),
+            ]);
+
+          const [previousGatekeeperBalance, previousPartnerBalance] =
+            await getBalances();
+
+          await service.issue(authority, derivedPass);
+
+          const [newGatekeeperBalance, newPartnerBalance] = await getBalances();
+          expect(newGatekeeperBalance - previousGatekeeperBalance).to.equal(
+            (fee0 * 7) / 10 + 2 * fee1
+          );
+          expect(newPartnerBalance - previousPartnerBalance).to.equal(
+            (fee0 * 3) / 10
+          );
+
+          // restore payment to the gatekeeper for the following tests
+          await civicGatekeeperDerivedPassService.setFee(
+            sourceGkns[0].publicKey,
+            fee0
+          );
+        });
+
+        it("should reject fee splits that do not sum to 100%", () => {
+          const shouldFail = civicGatekeeperDerivedPassService.setFee(
+            sourceGkns[0].publicKey,
+            fee0,
+            0,
+            0,
+            "IssuerOnly",
+            undefined,
+            {
+              splits: [
+                { recipient: civicGatekeeper.publicKey, basisPoints: 5_000 },
+              ],
+            }
+          );
+
+          return expect(shouldFail).to.be.rejectedWith(/InvalidFeeSplit/);
+        });
+
+        it("should not escrow the fees of a fee override", () => {
+          const shouldFail = civicGatekeeperDerivedPassService.setFeeOverride(
+            sourceGkns[0].publicKey,
+            derivedPass,
+            fee0,
+            0,
+            0,
+            "IssuerOnly",
+            undefined,
+            { escrow: true }
+          );
+
+          return expect(shouldFail).to.be.rejectedWith(
+            /InvalidFeeOverrideRouting/
+          );
+        });
+
+        // Warning, this relies on the previous tests running first to set the fees
+        it("should charge the fee override for the derived pass", async () => {
+          const overrideFee = 10;
+          await civicGatekeeperDerivedPassService.setFeeOverride(
+            sourceGkns[0].publicKey,
+            derivedPass,
+            overrideFee
+          );
+
+          const [transactionSignature] = await service.issue(
+            authority,
+            derivedPass
+          );
+
+          const feesPaid = await service.getFeesPaid(transactionSignature);
+          expect(
+            feesPaid?.fees.map((fee) => fee.amount.toNumber())
+          ).to.deep.equal([overrideFee, 2 * fee1]);
+        });
+
+        // Warning, this relies on the previous test running first to set the fee override
+        it("should schedule and cancel a change to a fee override", async () => {
+          const effectiveTime = Math.floor(Date.now() / 1000) + 60 * 60;
+          await civicGatekeeperDerivedPassService.scheduleFeeOverrideUpdate(
+            sourceGkns[0].publicKey,
+            derivedPass,
+            effectiveTime,
+            2 * fee0
+          );
+
+          const [feeOverrideAddress] = await util.deriveFeeOverrideAddress(
+            civicGatekeeper.publicKey,
+            sourceGkns[0].publicKey,
+            derivedPass,
+            program
+          );
+          let feeOverride = await program.account.fee.fetch(feeOverrideAddress);
+          expect(feeOverride.pending?.issueAmount.toNumber()).to.equal(
+            2 * fee0
+          );
+
+          await civicGatekeeperDerivedPassService.cancelPendingFeeOverride(
+            sourceGkns[0].publicKey,
+            derivedPass
+          );
+          feeOverride = await program.account.fee.fetch(feeOverrideAddress);
+          expect(feeOverride.pending).to.be.null;
+
+          // remove the override, so that the following tests are charged the generic fee
+          await civicGatekeeperDerivedPassService.unsetFeeOverride(
+            sourceGkns[0].publicKey,
+            derivedPass
+          );
+        });
+
+        // Warning, this relies on the previous tests running first to set the fees
+        it("should charge the current fee until a scheduled fee change takes effect", async () => {
+          const effectiveTime = Math.floor(Date.now() / 1000) + 60 * 60;
+          await civicGatekeeperDerivedPassService.scheduleFeeUpdate(
+            sourceGkns[0].publicKey,
+            effectiveTime,
+            3 * fee0
+          );
+
+          const [transactionSignature] = await service.issue(
+            authority,
+            derivedPass
+          );
+
+          const feesPaid = await service.getFeesPaid(transactionSignature);
+          expect(
+            feesPaid?.fees.map((fee) => fee.amount.toNumber())
+          ).to.deep.equal([fee0, 2 * fee1]);
+
+          await expect(
+            service.applyPendingFee(
+              civicGatekeeper.publicKey,
+              sourceGkns[0].publicKey
+            )
+          ).to.be.rejectedWith(/PendingFeeNotEffective/);
+
+          await civicGatekeeperDerivedPassService.cancelPendingFee(
+            sourceGkns[0].publicKey
+          );
+          const [feeAddress] = await deriveGatekeeperFeeAddress(
+            civicGatekeeper.publicKey,
+            sourceGkns[0].publicKey,
+            program
+          );
+          const feeAccount = await program.account.fee.fetch(feeAddress);
+          expect(feeAccount.pending).to.be.null;
+        });
+
+        it("should reject a fee exceeding the per-component fee cap of the derived pass", async () => {
+          const authorityService = new DerivedPassService(
+            program,
+            authorityProvider
+          );
+          const [, cappedDerivedPass] = await authorityService.derivePass(
+            sourceGknKeys,
+            { feeCaps: { maxIssuePerComponent: 50 } }
+          );
+
+          const shouldFail = service.issue(authority, cappedDerivedPass);
+
+          return expect(shouldFail).to.be.rejectedWith(/FeeCapExceeded/);
+        });
+
+        it("should reject fees exceeding the fee caps of the derived pass", async () => {
+          const authorityService = new DerivedPassService(
+            program,
+            authorityProvider
+          );
+          const [, cappedDerivedPass] = await authorityService.derivePass(
+            sourceGknKeys,
+            { feeCaps: { maxIssueTotal: fee0 } }
+          );
+
+          const shouldFail = service.issue(authority, cappedDerivedPass);
+
+          return expect(shouldFail).to.be.rejectedWith(/FeeCapExceeded/);
+        });
+
+        it("should fail if an invalid fee account is passed", () => {
+          sandbox
+            .stub(util, "deriveGatekeeperFeeAddress")
+            .resolves([web3.Keypair.generate().publicKey, 255]);
+
+          const shouldFail = service.issue(authority, derivedPass);
+
+          return expect(shouldFail).to.be.rejectedWith(/InvalidFeeAccount/);
+        });
+
+        it("should fail if an incorrect gatekeeper account is passed", () => {
+          // stub toAccountMeta to pass an incorrect gatekeeper account for the gateekeeper
+          sandbox
+            .stub(util, "toAccountMeta")
+            .callsFake((isSigner, isWritable) => (pubkey: web3.PublicKey) => {
+              if (pubkey.equals(civicGatekeeper.publicKey))
+                return {
+                  pubkey: web3.Keypair.generate().publicKey, // dummy gatekeeper
+                  isSigner,
+                  isWritable,
+                };
+
+              return {
+                pubkey,
+                isSigner,
+                isWritable,
+              };
+            });
+
+          const shouldFail = service.issue(authority, derivedPass);
+
+          return expect(shouldFail).to.be.rejectedWith(/GatekeeperMismatch/);
+        });
+
+        it("should fail to remove the fee if not the owner", async () => {
+          // create a different gatekeeper that will attempt to remove the fee
+          const differentGatekeeper = web3.Keypair.generate();
+          const differentGatekeeperProvider = new AnchorProvider(
+            authorityProvider.connection,
+            new Wallet(differentGatekeeper),
+            AnchorProvider.defaultOptions()
+          );
+          const differentGatekeeperDerivedPassService = new DerivedPassService(
+            program,
+            differentGatekeeperProvider
+          );
+
+          // stub the client to pass the civic gatekeeper fee address
+          const deriveResult = await deriveGatekeeperFeeAddress(
+            civicGatekeeper.publicKey,
+            sourceGkns[1].publicKey,
+            program
+          );
+          sandbox
+            .stub(util, "deriveGatekeeperFeeAddress")
+            .resolves(deriveResult);
+
+          await fund(authorityProvider, differentGatekeeper.publicKey);
+
+          const shouldFail = differentGatekeeperDerivedPassService.unsetFee(
+            sourceGkns[1].publicKey
+          );
+
+          // The derived fee account address will not match the passed-in seeds
+          return expect(shouldFail).to.be.rejectedWith(/ConstraintSeeds/);
+        });
+
+        it("should remove the fee", async () => {
+          await civicGatekeeperDerivedPassService.unsetFee(
+            sourceGkns[1].publicKey
+          );
+
+          await new Promise((resolve) => setTimeout(resolve, 5000));
+
+          const [feeAddress] = await deriveGatekeeperFeeAddress(
+            civicGatekeeper.publicKey,
+            sourceGkns[1].publicKey,
+            program
+          );
+
+          const account = await authorityProvider.connection.getAccountInfo(
+            feeAddress
+          );
+
+          expect(account).to.be.null;
+
+          const previousGatekeeperBalance =
+            await authorityProvider.connection.getBalance(
+              civicGatekeeper.publicKey
+            );
+
+          await service.issue(authority, derivedPass);
+
+          const newGatekeeperBalance =
+            await authorityProvider.connection.getBalance(
+              civicGatekeeper.publicKey
+            );
+
+          // no fee1 incurred this time.
+          const expectedTotalFee = fee0;
+          expect(newGatekeeperBalance - previousGatekeeperBalance).to.equal(
             expectedTotalFee
           );
         });
+
+        // Warning, this relies on the previous test running first to remove the fee
+        it("should charge the network default fee if the gatekeeper has no fee", async () => {
+          const networkFee = 500;
+          const networkService = new DerivedPassService(
+            program,
+            new AnchorProvider(
+              authorityProvider.connection,
+              new Wallet(sourceGkns[1]),
+              AnchorProvider.defaultOptions()
+            )
+          );
+          await networkService.setNetworkFee(networkFee);
+
+          const [transactionSignature] = await service.issue(
+            authority,
+            derivedPass
+          );
+
+          const feesPaid = await service.getFeesPaid(transactionSignature);
+          expect(
+            feesPaid?.fees.map((fee) => fee.amount.toNumber())
+          ).to.deep.equal([fee0, networkFee]);
+
+          await networkService.unsetNetworkFee();
+        });
+
+        it("should not allow the network to route its default fee away from the gatekeeper", () => {
+          const networkService = new DerivedPassService(
+            program,
+            new AnchorProvider(
+              authorityProvider.connection,
+              new Wallet(sourceGkns[1]),
+              AnchorProvider.defaultOptions()
+            )
+          );
+
+          const shouldFail = networkService.setNetworkFee(
+            500,
+            0,
+            0,
+            "IssuerOnly",
+            undefined,
+            { treasury: sourceGkns[1].publicKey }
+          );
+
+          return expect(shouldFail).to.be.rejectedWith(
+            /InvalidNetworkFeeRouting/
+          );
+        });
+
+        context("with a holder discount", () => {
+          // holders of a pass on the loyalty network get half price on the first component pass
+          const loyaltyGkn = web3.Keypair.generate();
+          let loyaltyGatekeeperService: GatekeeperService;
+
+          before("set up the loyalty network and the discount", async () => {
+            await fund(authorityProvider, loyaltyGkn.publicKey);
+            loyaltyGatekeeperService = await addGatekeeper(
+              authorityProvider,
+              loyaltyGkn,
+              civicGatekeeper
+            );
+
+            await civicGatekeeperDerivedPassService.setFee(
+              sourceGkns[0].publicKey,
+              fee0,
+              0,
+              0,
+              "IssuerOnly",
+              { gatekeeperNetwork: loyaltyGkn.publicKey, percentage: 50 }
+            );
+          });
+
+          after("remove the discount", () =>
+            civicGatekeeperDerivedPassService.setFee(
+              sourceGkns[0].publicKey,
+              fee0
+            )
+          );
+
+          const issueAndGetFees = async () => {
+            const [transactionSignature] = await service.issue(
+              authority,
+              derivedPass
+            );
+            const feesPaid = await service.getFeesPaid(transactionSignature);
+            return feesPaid?.fees.map((fee) => fee.amount.toNumber());
+          };
+
+          it("should apply the discount if the recipient holds a loyalty pass", async () => {
+            await sendGatewayTransaction(() =>
+              loyaltyGatekeeperService.issue(owner.publicKey)
+            );
+
+            expect(await issueAndGetFees()).to.deep.equal([fee0 / 2, 0]);
+          });
+
+          it("should not apply the discount if the recipient holds no loyalty pass", async () => {
+            expect(await issueAndGetFees()).to.deep.equal([fee0, 0]);
+          });
+
+          it("should ignore a loyalty pass that is no longer valid", async () => {
+            const loyaltyPass = await sendGatewayTransaction(() =>
+              loyaltyGatekeeperService.issue(owner.publicKey)
+            );
+            await sendGatewayTransaction(() =>
+              loyaltyGatekeeperService.freeze(loyaltyPass.publicKey)
+            );
+
+            // the frozen pass is still passed by the client, but does not earn the discount
+            expect(await issueAndGetFees()).to.deep.equal([fee0, 0]);
+          });
+        });
       });
     });
   });
@@ -390,6 +1371,83 @@ describe("gateway-derive", () => {
         now + EXPIRE_DURATION - buffer
       );
     });
+
+    it("should derive a pass expiring at the fixed expiry time if it is earlier", async () => {
+      const authorityService = new DerivedPassService(
+        program,
+        authorityProvider
+      );
+      const expireAt = Math.floor(Date.now() / 1000) + EXPIRE_DURATION / 2;
+      const [, seasonDerivedPass] = await authorityService.derivePass(
+        sourceGknKeys,
+        { expireDuration: EXPIRE_DURATION, expireAt }
+      );
+
+      await service.issue(authority, seasonDerivedPass);
+
+      const foundToken = await findGatewayToken(
+        authorityProvider.connection,
+        owner.publicKey,
+        seasonDerivedPass
+      );
+      expect(foundToken?.expiryTime).to.equal(expireAt);
+    });
+
+    // Warning, this relies on the first test running first to issue the derived pass
+    it("should not refresh a derived pass using a gateway token of another derived pass", async () => {
+      const authorityService = new DerivedPassService(
+        program,
+        authorityProvider
+      );
+      const [, otherDerivedPass] = await authorityService.derivePass(
+        sourceGknKeys,
+        { expireDuration: EXPIRE_DURATION }
+      );
+      const [, otherGatewayToken] = await service.issue(
+        authority,
+        otherDerivedPass
+      );
+
+      const shouldFail = service.refresh(
+        otherGatewayToken,
+        authority,
+        derivedPass
+      );
+
+      return expect(shouldFail).to.be.rejectedWith(/InvalidGatewayToken/);
+    });
+
+    // Warning, this relies on the first test running first to issue the derived pass
+    it("should not refresh a gateway token held by another wallet", async () => {
+      const otherWallet = web3.Keypair.generate();
+      await fund(authorityProvider, otherWallet.publicKey);
+      await Promise.all(
+        civicGatekeeperServices.map((gks) =>
+          sendGatewayTransaction(() => gks.issue(otherWallet.publicKey))
+        )
+      );
+      const otherService = new DerivedPassService(
+        program,
+        new AnchorProvider(
+          authorityProvider.connection,
+          new Wallet(otherWallet),
+          AnchorProvider.defaultOptions()
+        )
+      );
+      const gatewayToken =
+        await getGatewayTokenAddressForOwnerAndGatekeeperNetwork(
+          owner.publicKey,
+          derivedPass
+        );
+
+      const shouldFail = otherService.refresh(
+        gatewayToken,
+        authority,
+        derivedPass
+      );
+
+      return expect(shouldFail).to.be.rejectedWith(/InvalidGatewayToken/);
+    });
   });
 
   context("with expire-on-use", () => {
@@ -490,4 +1548,377 @@ describe("gateway-derive", () => {
       await expect(shouldFail).to.be.rejectedWith(/RefreshDisabled/);
     });
   });
+
+  context("refresh a pass with refresh limits", () => {
+    let authorityService: DerivedPassService;
+
+    // issue the derived pass with the given properties and return its gateway token
+    const issueDerivedPass = async (properties: Properties) => {
+      [, derivedPass] = await authorityService.derivePass(
+        sourceGknKeys,
+        properties
+      );
+      await service.issue(authority, derivedPass);
+
+      const foundToken = await findGatewayToken(
+        authorityProvider.connection,
+        owner.publicKey,
+        derivedPass
+      );
+      if (!foundToken) throw new Error("Token not found");
+      return foundToken.publicKey;
+    };
+
+    before(
+      "set up the owner (recipient) of the pass and fund them",
+      createOwner
+    );
+
+    before("issue the component passes", async () => {
+      authorityService = new DerivedPassService(program, authorityProvider);
+      service = new DerivedPassService(program, ownerProvider);
+
+      await Promise.all(
+        civicGatekeeperServices.map((gks) =>
+          sendGatewayTransaction(() => gks.issue(owner.publicKey))
+        )
+      );
+    });
+
+    it("should not allow a refresh before the minimum refresh interval", async () => {
+      const gatewayToken = await issueDerivedPass({
+        expireDuration: 3600,
+        minRefreshInterval: 3600,
+      });
+
+      const shouldFail = service.refresh(gatewayToken, authority, derivedPass);
+
+      await expect(shouldFail).to.be.rejectedWith(/RefreshTooSoon/);
+    });
+
+    it("should not allow more than the maximum number of refreshes", async () => {
+      const gatewayToken = await issueDerivedPass({
+        expireDuration: 3600,
+        maxRefreshes: 1,
+      });
+
+      await service.refresh(gatewayToken, authority, derivedPass);
+
+      const recipientState = await service.getRecipientState(derivedPass);
+      expect(recipientState?.refreshCount).to.equal(1);
+
+      const shouldFail = service.refresh(gatewayToken, authority, derivedPass);
+
+      await expect(shouldFail).to.be.rejectedWith(/MaxRefreshesReached/);
+    });
+
+    it("should not allow a refresh before the refresh window", async () => {
+      const gatewayToken = await issueDerivedPass({
+        expireDuration: 3600,
+        refreshWindow: 60,
+      });
+
+      const shouldFail = service.refresh(gatewayToken, authority, derivedPass);
+
+      await expect(shouldFail).to.be.rejectedWith(/OutsideRefreshWindow/);
+    });
+
+    it("should extend the current expiry on refresh, if the derived pass extends from expiry", async () => {
+      const expireDuration = 3600;
+      const gatewayToken = await issueDerivedPass({
+        expireDuration,
+        refreshFromExpiry: { maxHorizon: 3 * expireDuration },
+      });
+      const issuedToken = await findGatewayToken(
+        authorityProvider.connection,
+        owner.publicKey,
+        derivedPass
+      );
+
+      await service.refresh(gatewayToken, authority, derivedPass);
+
+      const refreshedToken = await findGatewayToken(
+        authorityProvider.connection,
+        owner.publicKey,
+        derivedPass
+      );
+      expect(refreshedToken?.expiryTime).to.equal(
+        (issuedToken?.expiryTime || 0) + expireDuration
+      );
+    });
+  });
+
+  context("with linked wallets", () => {
+    // the wallet holding the component passes
+    let coldWallet: web3.Keypair;
+
+    before(
+      "set up the owner (recipient) of the pass and fund them",
+      createOwner
+    );
+
+    before(
+      "set up the cold wallet and issue the component passes",
+      async () => {
+        coldWallet = web3.Keypair.generate();
+        await fund(authorityProvider, coldWallet.publicKey);
+
+        await Promise.all(
+          civicGatekeeperServices.map((gks) =>
+            sendGatewayTransaction(() => gks.issue(coldWallet.publicKey))
+          )
+        );
+      }
+    );
+
+    before("link the owner to the cold wallet", async () => {
+      service = new DerivedPassService(program, ownerProvider);
+      await service.linkWallet(new Wallet(coldWallet));
+    });
+
+    it("should not derive a pass from a linked wallet if not allowed by the derived pass", async () => {
+      const authorityService = new DerivedPassService(
+        program,
+        authorityProvider
+      );
+      [, derivedPass] = await authorityService.derivePass(sourceGknKeys);
+
+      const shouldFail = service.issue(
+        authority,
+        derivedPass,
+        coldWallet.publicKey
+      );
+
+      return expect(shouldFail).to.be.rejectedWith(/LinkedWalletsDisabled/);
+    });
+
+    it("should derive a pass using component passes from a linked wallet", async () => {
+      const authorityService = new DerivedPassService(
+        program,
+        authorityProvider
+      );
+      [, derivedPass] = await authorityService.derivePass(sourceGknKeys, {
+        allowLinkedWallets: true,
+      });
+
+      const [, gatewayToken] = await service.issue(
+        authority,
+        derivedPass,
+        coldWallet.publicKey
+      );
+
+      const foundToken = await findGatewayToken(
+        authorityProvider.connection,
+        owner.publicKey,
+        derivedPass
+      );
+      expect(foundToken?.publicKey.toBase58()).to.equal(
+        gatewayToken.toBase58()
+      );
+    });
+  });
+
+  // load a keypair used by the accounts loaded into the test validator (see Anchor.toml)
+  const loadKeypair = (name: string) =>
+    web3.Keypair.fromSecretKey(
+      Uint8Array.from(
+        JSON.parse(readFileSync(`tests/fixtures/${name}.json`, "utf8"))
+      )
+    );
+
+  context("with a derived pass created before account versioning", () => {
+    // a version 0 derived pass, loaded into the test validator (see Anchor.toml),
+    // made up of a single component pass
+    const legacyDerivedPass = loadKeypair("derivedPassV0Keypair");
+    const legacySourceGkn = loadKeypair("derivedPassV0SourceGknKeypair");
+
+    let legacyAuthority: web3.PublicKey;
+    let legacySourceGknService: GatekeeperService;
+
+    before("add the gatekeepers to the networks", async () => {
+      await fund(authorityProvider, legacySourceGkn.publicKey);
+      legacySourceGknService = await addGatekeeper(
+        authorityProvider,
+        legacySourceGkn,
+        civicGatekeeper
+      );
+
+      // the derived gatekeeper is added to the network when a derived pass is created,
+      // so would already be a gatekeeper of a real version 0 derived pass
+      ({ authority: legacyAuthority } = await fetchDerivedPass(
+        program,
+        legacyDerivedPass.publicKey
+      ));
+      const [derivedGatekeeper] = await util.deriveGatekeeper(
+        legacyAuthority,
+        program
+      );
+      const derivedGatekeeperAccount = await util.deriveGatekeeperAccount(
+        derivedGatekeeper,
+        legacyDerivedPass.publicKey
+      );
+      await authorityProvider.sendAndConfirm(
+        new web3.Transaction().add(
+          addGatekeeperInstruction(
+            authority,
+            derivedGatekeeperAccount,
+            derivedGatekeeper,
+            legacyDerivedPass.publicKey
+          )
+        ),
+        [legacyDerivedPass]
+      );
+    });
+
+    const issueAndRefresh = async () => {
+      await createOwner();
+      await sendGatewayTransaction(() =>
+        legacySourceGknService.issue(owner.publicKey)
+      );
+      service = new DerivedPassService(program, ownerProvider);
+
+      const [, gatewayToken] = await service.issue(
+        legacyAuthority,
+        legacyDerivedPass.publicKey
+      );
+      await service.refresh(
+        gatewayToken,
+        legacyAuthority,
+        legacyDerivedPass.publicKey
+      );
+
+      const foundToken = await findGatewayToken(
+        authorityProvider.connection,
+        owner.publicKey,
+        legacyDerivedPass.publicKey
+      );
+      expect(foundToken?.state).to.equal(State.ACTIVE);
+    };
+
+    it("should set a fee override for a derived pass before it is migrated", async () => {
+      const civicGatekeeperService = new DerivedPassService(
+        program,
+        new AnchorProvider(
+          authorityProvider.connection,
+          new Wallet(civicGatekeeper),
+          AnchorProvider.defaultOptions()
+        )
+      );
+      await civicGatekeeperService.setFeeOverride(
+        legacySourceGkn.publicKey,
+        legacyDerivedPass.publicKey,
+        100
+      );
+
+      const [feeOverrideAddress] = await util.deriveFeeOverrideAddress(
+        civicGatekeeper.publicKey,
+        legacySourceGkn.publicKey,
+        legacyDerivedPass.publicKey,
+        program
+      );
+      const feeOverride = await fetchFeeNullable(program, feeOverrideAddress);
+      expect(feeOverride?.issueAmount.toNumber()).to.equal(100);
+    });
+
+    it("should issue and refresh a pass before the derived pass is migrated", async () => {
+      const derivedPassAccount = await fetchDerivedPass(
+        program,
+        legacyDerivedPass.publicKey
+      );
+      expect(derivedPassAccount.version).to.equal(0);
+      expect(derivedPassAccount.properties.expireDuration?.toNumber()).to.equal(
+        3600
+      );
+
+      await issueAndRefresh();
+    });
+
+    it("should issue and refresh a pass after the derived pass is migrated", async () => {
+      const authorityService = new DerivedPassService(
+        program,
+        authorityProvider
+      );
+      await authorityService.migrateDerivedPass(legacyDerivedPass.publicKey);
+
+      const derivedPassAccount = await program.account.derivedPass.fetch(
+        legacyDerivedPass.publicKey
+      );
+      expect(derivedPassAccount.version).to.equal(1);
+      expect(derivedPassAccount.authority.toBase58()).to.equal(
+        legacyAuthority.toBase58()
+      );
+      expect(derivedPassAccount.sourceGkns[0].toBase58()).to.equal(
+        legacySourceGkn.publicKey.toBase58()
+      );
+      expect(derivedPassAccount.properties.expireDuration?.toNumber()).to.equal(
+        3600
+      );
+
+      await issueAndRefresh();
+
+      const { counters } = await fetchDerivedPass(
+        program,
+        legacyDerivedPass.publicKey
+      );
+      expect(counters.issued.toNumber()).to.equal(1);
+    });
+  });
+
+  context("with a fee created before account versioning", () => {
+    // a version 0 SOL fee of 1000 lamports on issue, loaded into the test validator (see Anchor.toml)
+    const legacyFeeGatekeeper = loadKeypair("feeV0GatekeeperKeypair");
+    const { publicKey: legacyFeeNetwork } = loadKeypair(
+      "derivedPassV0SourceGknKeypair"
+    );
+
+    let legacyFeeAddress: web3.PublicKey;
+    let gatekeeperService: DerivedPassService;
+
+    before("set up the gatekeeper of the fee", async () => {
+      await fund(authorityProvider, legacyFeeGatekeeper.publicKey);
+      gatekeeperService = new DerivedPassService(
+        program,
+        new AnchorProvider(
+          authorityProvider.connection,
+          new Wallet(legacyFeeGatekeeper),
+          AnchorProvider.defaultOptions()
+        )
+      );
+      [legacyFeeAddress] = await deriveGatekeeperFeeAddress(
+        legacyFeeGatekeeper.publicKey,
+        legacyFeeNetwork,
+        program
+      );
+    });
+
+    it("should not update the fee before it is migrated", async () => {
+      const fee = await fetchFeeNullable(program, legacyFeeAddress);
+      expect(fee?.version).to.equal(0);
+      expect(fee?.issueAmount.toNumber()).to.equal(1000);
+
+      const shouldFail = gatekeeperService.setFee(legacyFeeNetwork, 2000);
+
+      return expect(shouldFail).to.be.rejectedWith(/AccountNotMigrated/);
+    });
+
+    it("should update the fee after it is migrated", async () => {
+      await gatekeeperService.migrateFee(legacyFeeAddress);
+
+      const treasury = web3.Keypair.generate().publicKey;
+      await gatekeeperService.setFee(
+        legacyFeeNetwork,
+        500,
+        0,
+        0,
+        "IssuerOnly",
+        undefined,
+        { treasury }
+      );
+
+      const fee = await program.account.fee.fetch(legacyFeeAddress);
+      expect(fee.version).to.equal(1);
+      expect(fee.issueAmount.toNumber()).to.equal(500);
+      expect(fee.routing.treasury?.toBase58()).to.equal(treasury.toBase58());
+    });
+  });
 });